@@ -1,5 +1,11 @@
 const githubUrl = "https://github.com";
 if (window.location.href.startsWith(githubUrl)) {
-    const rxGithubUrl = "https://rxgithub.com" + window.location.href.substring(githubUrl.length);
-    navigator.clipboard.writeText(rxGithubUrl);
+    const url = new URL("https://rxgithub.com" + window.location.pathname + window.location.search);
+    const lines = window.location.hash.match(/^#(L\d+(?:-L\d+)?)$/);
+    if (lines) {
+        url.searchParams.set("lines", lines[1]);
+    } else {
+        url.hash = window.location.hash;
+    }
+    navigator.clipboard.writeText(url.toString());
 }
\ No newline at end of file
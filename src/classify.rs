@@ -0,0 +1,387 @@
+use futures_util::{stream, Stream, StreamExt};
+
+/// The number of bytes at the start of a file that are read to tell what it is.
+pub(crate) const SNIFF_BYTES: usize = 1024;
+
+/// What a file in a repository is, which decides how it's shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FileKind {
+    Text,
+    RasterImage,
+    Svg,
+    Video,
+    Audio,
+    Pdf,
+    Archive,
+    Binary,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FileType {
+    pub(crate) kind: FileKind,
+    pub(crate) mime: String,
+}
+
+impl FileType {
+    fn new(kind: FileKind, mime: &str) -> Self {
+        Self {
+            kind,
+            mime: mime.to_owned(),
+        }
+    }
+}
+
+/// A file on raw.githubusercontent.com, from [`fetch_file_type`].
+pub(crate) struct RemoteFile {
+    pub(crate) file_type: FileType,
+    /// The size of the whole file in bytes, when the server says it.
+    pub(crate) size: Option<u64>,
+}
+
+/// Tells what a file is from its first bytes, its path and the `Content-Type` it was served with,
+/// in that order. raw.githubusercontent.com serves most files as `text/plain` or
+/// `application/octet-stream`, so the header is only a last resort.
+pub(crate) fn classify(path: &str, content_type: &str, head: &[u8]) -> FileType {
+    let extension = path
+        .rsplit('/')
+        .next()
+        .and_then(|file_name| file_name.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    if let Some(file_type) = sniff_magic(head, &extension) {
+        return file_type;
+    }
+    if !head.is_empty() && looks_like_text(head) {
+        return if extension == "svg"
+            || content_type.contains("image/svg+xml")
+            || starts_with_svg(head)
+        {
+            FileType::new(FileKind::Svg, "image/svg+xml")
+        } else {
+            FileType::new(FileKind::Text, "text/plain")
+        };
+    }
+    from_extension(&extension).unwrap_or_else(|| from_content_type(content_type))
+}
+
+fn sniff_magic(head: &[u8], extension: &str) -> Option<FileType> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    let file_type = |kind, mime| Some(FileType::new(kind, mime));
+
+    if at(0, b"\x89PNG\r\n\x1a\n") {
+        file_type(FileKind::RasterImage, "image/png")
+    } else if at(0, &[0xFF, 0xD8, 0xFF]) {
+        file_type(FileKind::RasterImage, "image/jpeg")
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        file_type(FileKind::RasterImage, "image/gif")
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        file_type(FileKind::RasterImage, "image/webp")
+    } else if at(0, b"BM") && extension == "bmp" {
+        // Two bytes are too few to go by alone.
+        file_type(FileKind::RasterImage, "image/bmp")
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        file_type(FileKind::RasterImage, "image/tiff")
+    } else if at(0, &[0, 0, 1, 0]) && extension == "ico" {
+        file_type(FileKind::RasterImage, "image/x-icon")
+    } else if at(4, b"ftyp") {
+        match head.get(8..12)? {
+            // Images that can't be decoded, so there's nothing to draw for them.
+            b"avif" | b"avis" => file_type(FileKind::Binary, "image/avif"),
+            b"heic" | b"heix" | b"mif1" => file_type(FileKind::Binary, "image/heic"),
+            b"M4A " | b"M4B " => file_type(FileKind::Audio, "audio/mp4"),
+            b"qt  " => file_type(FileKind::Video, "video/quicktime"),
+            b"M4V " => file_type(FileKind::Video, "video/x-m4v"),
+            _ => file_type(FileKind::Video, "video/mp4"),
+        }
+    } else if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        if extension == "mkv" {
+            file_type(FileKind::Video, "video/x-matroska")
+        } else {
+            file_type(FileKind::Video, "video/webm")
+        }
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        file_type(FileKind::Video, "video/x-msvideo")
+    } else if at(0, b"OggS") {
+        if extension == "ogv" {
+            file_type(FileKind::Video, "video/ogg")
+        } else {
+            file_type(FileKind::Audio, "audio/ogg")
+        }
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        file_type(FileKind::Audio, "audio/wav")
+    } else if at(0, b"fLaC") {
+        file_type(FileKind::Audio, "audio/flac")
+    } else if at(0, b"ID3") {
+        file_type(FileKind::Audio, "audio/mpeg")
+    } else if at(0, b"%PDF-") {
+        file_type(FileKind::Pdf, "application/pdf")
+    } else if at(0, b"PK\x03\x04") || at(0, b"PK\x05\x06") {
+        file_type(FileKind::Archive, "application/zip")
+    } else if at(0, &[0x1F, 0x8B]) {
+        file_type(FileKind::Archive, "application/gzip")
+    } else if at(0, b"BZh") {
+        file_type(FileKind::Archive, "application/x-bzip2")
+    } else if at(0, &[0xFD, b'7', b'z', b'X', b'Z', 0]) {
+        file_type(FileKind::Archive, "application/x-xz")
+    } else if at(0, &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]) {
+        file_type(FileKind::Archive, "application/x-7z-compressed")
+    } else if at(0, b"Rar!\x1A\x07") {
+        file_type(FileKind::Archive, "application/vnd.rar")
+    } else if at(0, &[0x28, 0xB5, 0x2F, 0xFD]) {
+        file_type(FileKind::Archive, "application/zstd")
+    } else if at(257, b"ustar") {
+        file_type(FileKind::Archive, "application/x-tar")
+    } else {
+        None
+    }
+}
+
+/// Whether `head` is UTF-8 without any NUL bytes, allowing for a character that's cut off at the
+/// end.
+fn looks_like_text(head: &[u8]) -> bool {
+    !head.contains(&0)
+        && match std::str::from_utf8(head) {
+            Ok(_) => true,
+            Err(error) => error.error_len().is_none(),
+        }
+}
+
+/// Whether the first element of an XML document is `<svg`, after its declaration, comments and
+/// doctype.
+fn starts_with_svg(head: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(head);
+    let mut rest = text.trim_start_matches('\u{FEFF}').trim_start();
+    loop {
+        let skipped = if rest.starts_with("<?") {
+            rest.find("?>").map(|end| &rest[end + 2..])
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| &rest[end + 3..])
+        } else if rest.starts_with("<!") {
+            rest.find('>').map(|end| &rest[end + 1..])
+        } else {
+            return rest.starts_with("<svg");
+        };
+        match skipped {
+            Some(skipped) => rest = skipped.trim_start(),
+            None => return false,
+        }
+    }
+}
+
+fn from_extension(extension: &str) -> Option<FileType> {
+    let (kind, mime) = match extension {
+        "png" => (FileKind::RasterImage, "image/png"),
+        "jpg" | "jpeg" => (FileKind::RasterImage, "image/jpeg"),
+        "gif" => (FileKind::RasterImage, "image/gif"),
+        "webp" => (FileKind::RasterImage, "image/webp"),
+        "bmp" => (FileKind::RasterImage, "image/bmp"),
+        "tif" | "tiff" => (FileKind::RasterImage, "image/tiff"),
+        "ico" => (FileKind::RasterImage, "image/x-icon"),
+        "svg" => (FileKind::Svg, "image/svg+xml"),
+        "mp4" => (FileKind::Video, "video/mp4"),
+        "m4v" => (FileKind::Video, "video/x-m4v"),
+        "mov" => (FileKind::Video, "video/quicktime"),
+        "webm" => (FileKind::Video, "video/webm"),
+        "mkv" => (FileKind::Video, "video/x-matroska"),
+        "mp3" => (FileKind::Audio, "audio/mpeg"),
+        "m4a" => (FileKind::Audio, "audio/mp4"),
+        "wav" => (FileKind::Audio, "audio/wav"),
+        "flac" => (FileKind::Audio, "audio/flac"),
+        "ogg" | "oga" | "opus" => (FileKind::Audio, "audio/ogg"),
+        "pdf" => (FileKind::Pdf, "application/pdf"),
+        "zip" | "jar" => (FileKind::Archive, "application/zip"),
+        "gz" | "tgz" => (FileKind::Archive, "application/gzip"),
+        "tar" => (FileKind::Archive, "application/x-tar"),
+        "7z" => (FileKind::Archive, "application/x-7z-compressed"),
+        "rar" => (FileKind::Archive, "application/vnd.rar"),
+        _ => return None,
+    };
+    Some(FileType::new(kind, mime))
+}
+
+/// The types of images, after `image/`, that the image crate can decode.
+const DECODED_IMAGE_SUBTYPES: &[&str] = &[
+    "png",
+    "jpeg",
+    "jpg",
+    "gif",
+    "webp",
+    "bmp",
+    "tiff",
+    "x-icon",
+    "vnd.microsoft.icon",
+];
+
+fn from_content_type(content_type: &str) -> FileType {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let kind = match mime.split_once('/') {
+        Some((_, "svg+xml")) => FileKind::Svg,
+        Some(("text", _)) => FileKind::Text,
+        Some(("image", subtype)) if DECODED_IMAGE_SUBTYPES.contains(&subtype) => {
+            FileKind::RasterImage
+        }
+        Some(("video", _)) => FileKind::Video,
+        Some(("audio", _)) => FileKind::Audio,
+        Some((_, "pdf")) => FileKind::Pdf,
+        Some((_, "zip" | "gzip" | "x-tar" | "x-7z-compressed" | "vnd.rar")) => FileKind::Archive,
+        _ => return FileType::new(FileKind::Binary, "application/octet-stream"),
+    };
+    FileType { kind, mime }
+}
+
+/// Reads the first [`SNIFF_BYTES`] of a body, or more if they come in one chunk, and returns them
+/// along with the whole body.
+pub(crate) async fn peek<S, B, E>(mut body: S) -> (Vec<u8>, impl Stream<Item = Result<B, E>>)
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let mut head = Vec::new();
+    let mut chunks = Vec::new();
+    while head.len() < SNIFF_BYTES {
+        let Some(chunk) = body.next().await else {
+            break;
+        };
+        if let Ok(chunk) = &chunk {
+            head.extend_from_slice(chunk.as_ref());
+        }
+        chunks.push(chunk);
+    }
+    (head, stream::iter(chunks).chain(body))
+}
+
+/// Classifies a file from the first bytes of it, which are fetched with a range request.
+pub(crate) async fn fetch_file_type(url: &str, path: &str) -> reqwest::Result<RemoteFile> {
+    let response = reqwest::Client::new()
+        .get(url)
+        .header("Range", format!("bytes=0-{}", SNIFF_BYTES - 1))
+        .send()
+        .await?;
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let content_type = header("Content-Type").unwrap_or_default();
+    // A partial response says the size of the whole file after the range, as in
+    // `bytes 0-1023/146515`.
+    let size = match header("Content-Range") {
+        Some(range) => range
+            .rsplit_once('/')
+            .and_then(|(_, size)| size.parse().ok()),
+        None => header("Content-Length").and_then(|length| length.parse().ok()),
+    };
+
+    let (head, _) = peek(response.bytes_stream()).await;
+    Ok(RemoteFile {
+        file_type: classify(path, &content_type, &head[..head.len().min(SNIFF_BYTES)]),
+        size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(path: &str, content_type: &str, head: &[u8]) -> (FileKind, String) {
+        let file_type = classify(path, content_type, head);
+        (file_type.kind, file_type.mime)
+    }
+
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        let mut head = vec![0, 0, 0, 0x18];
+        head.extend(b"ftyp");
+        head.extend(brand);
+        head.extend([0; 12]);
+        head
+    }
+
+    #[test]
+    fn trusts_magic_bytes_over_the_extension() {
+        assert_eq!(
+            kind(
+                "notes.txt",
+                "text/plain",
+                b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"
+            ),
+            (FileKind::RasterImage, "image/png".to_owned())
+        );
+        assert_eq!(
+            kind("a.jar", "", b"PK\x03\x04\x14\0"),
+            (FileKind::Archive, "application/zip".to_owned())
+        );
+        assert_eq!(
+            kind("clip", "", &[0x1A, 0x45, 0xDF, 0xA3, 0x9F]),
+            (FileKind::Video, "video/webm".to_owned())
+        );
+        assert_eq!(
+            kind("clip.MKV", "", &[0x1A, 0x45, 0xDF, 0xA3, 0x9F]),
+            (FileKind::Video, "video/x-matroska".to_owned())
+        );
+        assert_eq!(
+            kind("a.mp4", "", &ftyp(b"isom")),
+            (FileKind::Video, "video/mp4".to_owned())
+        );
+        assert_eq!(
+            kind("a.m4a", "", &ftyp(b"M4A ")),
+            (FileKind::Audio, "audio/mp4".to_owned())
+        );
+    }
+
+    #[test]
+    fn leaves_undecodable_images_as_binary() {
+        assert_eq!(
+            kind("photo.avif", "", &ftyp(b"avif")),
+            (FileKind::Binary, "image/avif".to_owned())
+        );
+        assert_eq!(
+            kind("photo.heic", "", &ftyp(b"mif1")),
+            (FileKind::Binary, "image/heic".to_owned())
+        );
+        assert_eq!(kind("photo", "image/avif", b"").0, FileKind::Binary);
+        assert_eq!(kind("photo", "image/webp", b"").0, FileKind::RasterImage);
+    }
+
+    #[test]
+    fn tells_text_from_svg() {
+        assert_eq!(
+            kind("a.bin", "application/octet-stream", b"fn main() {}\n").0,
+            FileKind::Text
+        );
+        let svg = b"\xEF\xBB\xBF<?xml version=\"1.0\"?>\n<!-- drawn -->\n<!DOCTYPE svg>\n<svg/>";
+        assert_eq!(kind("logo", "text/plain", svg).0, FileKind::Svg);
+        assert_eq!(kind("logo.svg", "", b"<svg/>").0, FileKind::Svg);
+        assert_eq!(kind("page.html", "", b"<?xml?><html/>").0, FileKind::Text);
+        // An unfinished comment never gets to the root element.
+        assert_eq!(kind("a", "", b"<!-- <svg").0, FileKind::Text);
+    }
+
+    #[test]
+    fn allows_a_character_cut_off_at_the_end() {
+        let text = "caf\u{e9}".as_bytes();
+        assert_eq!(kind("a", "", &text[..text.len() - 1]).0, FileKind::Text);
+        assert_eq!(kind("a", "", b"caf\xC3(").0, FileKind::Binary);
+        assert_eq!(kind("a", "", b"a\0b").0, FileKind::Binary);
+    }
+
+    #[test]
+    fn falls_back_to_the_extension_and_then_the_content_type() {
+        assert_eq!(kind("a.PDF", "", b"").0, FileKind::Pdf);
+        assert_eq!(kind("a", "video/mp4; codecs=avc1", b"").0, FileKind::Video);
+        assert_eq!(
+            kind("a", "", b""),
+            (FileKind::Binary, "application/octet-stream".to_owned())
+        );
+        // Too short to be told apart from a file that starts with "BM".
+        assert_eq!(kind("a.txt", "", b"BM").0, FileKind::Text);
+        assert_eq!(kind("a.bmp", "", b"BM\0\0").0, FileKind::RasterImage);
+    }
+}
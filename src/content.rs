@@ -1,8 +1,11 @@
 use maud::{html, PreEscaped};
 
 use crate::{
+    gitattributes::FileAttributes,
+    image_generator::Format,
     routes::{GistPath, SrcPath},
     utils::Lines,
+    video::VideoMetadata,
 };
 
 pub(crate) trait Content {
@@ -12,7 +15,10 @@ pub(crate) trait Content {
 pub(crate) struct TextContent<'a> {
     pub(crate) path: &'a SrcPath,
     pub(crate) query_string: String,
+    pub(crate) format: Format,
     pub(crate) lines: Lines,
+    pub(crate) attributes: FileAttributes,
+    pub(crate) image_size: Option<(u32, u32)>,
     pub(crate) origin: String,
 }
 
@@ -32,19 +38,24 @@ impl<'a> Content for TextContent<'a> {
             self.path.path,
             self.query_string
         );
-        let og_description = format!(
-            "Lines {}-{} of {} from {}/{}@{}",
-            self.lines.from,
-            self.lines.to,
-            file_name,
-            self.path.author,
-            self.path.repository,
-            self.path.branch
+        let mut og_description = format!(
+            "{} of {} from {}/{}@{}",
+            self.lines, file_name, self.path.author, self.path.repository, self.path.branch
         );
+        match (self.attributes.generated, self.attributes.vendored) {
+            (true, true) => og_description.push_str(" (generated, vendored)"),
+            (true, false) => og_description.push_str(" (generated)"),
+            (false, true) => og_description.push_str(" (vendored)"),
+            (false, false) => {}
+        }
         html! {
             meta name="description" content=(og_description);
             meta property="og:image" content=(og_image);
-            meta property="og:image:type" content="image/png";
+            meta property="og:image:type" content=(self.format.mime_type());
+            @if let Some((width, height)) = self.image_size {
+                meta property="og:image:width" content=(width);
+                meta property="og:image:height" content=(height);
+            }
             meta property="og:title" content=(og_title);
             meta property="og:description" content=(og_description);
 
@@ -90,6 +101,9 @@ impl<'a> Content for ImageContent<'a> {
 
 pub(crate) struct SVGContent<'a> {
     pub(crate) path: &'a SrcPath,
+    pub(crate) query_string: String,
+    pub(crate) format: Format,
+    pub(crate) image_size: Option<(u32, u32)>,
     pub(crate) origin: String,
 }
 
@@ -101,8 +115,13 @@ impl<'a> Content for SVGContent<'a> {
             file_name, self.path.repository, self.path.author, self.path.branch
         );
         let og_image = format!(
-            "{}/image/{}/{}/{}/{}",
-            self.origin, self.path.author, self.path.repository, self.path.branch, self.path.path
+            "{}/image/{}/{}/{}/{}?{}",
+            self.origin,
+            self.path.author,
+            self.path.repository,
+            self.path.branch,
+            self.path.path,
+            self.query_string
         );
         let og_description = format!(
             "{} from {}/{}@{}",
@@ -111,7 +130,11 @@ impl<'a> Content for SVGContent<'a> {
         html! {
             meta name="description" content=(og_description);
             meta property="og:image" content=(og_image);
-            meta property="og:image:type" content="image/png";
+            meta property="og:image:type" content=(self.format.mime_type());
+            @if let Some((width, height)) = self.image_size {
+                meta property="og:image:width" content=(width);
+                meta property="og:image:height" content=(height);
+            }
             meta property="og:title" content=(og_title);
             meta property="og:description" content=(og_description);
 
@@ -123,10 +146,14 @@ impl<'a> Content for SVGContent<'a> {
     }
 }
 
+/// The size of the player for videos whose size is unknown, and the widest it gets.
+const PLAYER_SIZE: (u32, u32) = (1280, 720);
+
 pub(crate) struct VideoContent<'a> {
     pub(crate) path: &'a SrcPath,
     pub(crate) video_url: String,
     pub(crate) mime: String,
+    pub(crate) metadata: VideoMetadata,
     pub(crate) origin: String,
 }
 
@@ -145,10 +172,30 @@ impl<'a> Content for VideoContent<'a> {
             "{} from {}/{}@{}",
             file_name, self.path.author, self.path.repository, self.path.branch
         );
+        // The embed page shows the video no wider than the player.
+        let (player_width, player_height) = match self.metadata.size {
+            Some((width, height)) if width > PLAYER_SIZE.0 => (
+                PLAYER_SIZE.0,
+                (height as u64 * PLAYER_SIZE.0 as u64 / width as u64).max(1) as u32,
+            ),
+            Some(size) => size,
+            None => PLAYER_SIZE,
+        };
+        let duration_seconds = self
+            .metadata
+            .duration
+            .map(|duration| duration.as_secs_f64().round() as u64);
         html! {
             meta name="description" content=(og_description);
             meta property="og:video" content=(self.video_url);
             meta property="og:video:type" content=(self.mime);
+            @if let Some((width, height)) = self.metadata.size {
+                meta property="og:video:width" content=(width);
+                meta property="og:video:height" content=(height);
+            }
+            @if let Some(duration_seconds) = duration_seconds {
+                meta property="video:duration" content=(duration_seconds);
+            }
             meta property="og:title" content=(og_title);
             meta property="og:description" content=(og_description);
 
@@ -156,8 +203,8 @@ impl<'a> Content for VideoContent<'a> {
             meta name="twitter:card" content="player";
             meta name="twitter:description" content=(og_description);
             meta name="twitter:player" content=(video_embed_url);
-            meta name="twitter:player:width" content="1280";
-            meta name="twitter:player:height" content="720";
+            meta name="twitter:player:width" content=(player_width);
+            meta name="twitter:player:height" content=(player_height);
         }
     }
 }
@@ -165,7 +212,9 @@ impl<'a> Content for VideoContent<'a> {
 pub(crate) struct GistContent<'a> {
     pub(crate) path: &'a GistPath,
     pub(crate) query_string: String,
+    pub(crate) format: Format,
     pub(crate) lines: Lines,
+    pub(crate) image_size: Option<(u32, u32)>,
     pub(crate) origin: String,
 }
 
@@ -176,14 +225,15 @@ impl<'a> Content for GistContent<'a> {
             "{}/gist-image/{}/{}?{}",
             self.origin, self.path.author, self.path.id, self.query_string
         );
-        let og_description = format!(
-            "Lines {}-{} of {}/{}",
-            self.lines.from, self.lines.to, self.path.author, self.path.id
-        );
+        let og_description = format!("{} of {}/{}", self.lines, self.path.author, self.path.id);
         html! {
             meta name="description" content=(og_description);
             meta property="og:image" content=(og_image);
-            meta property="og:image:type" content="image/png";
+            meta property="og:image:type" content=(self.format.mime_type());
+            @if let Some((width, height)) = self.image_size {
+                meta property="og:image:width" content=(width);
+                meta property="og:image:height" content=(height);
+            }
             meta property="og:title" content=(og_title);
             meta property="og:description" content=(og_description);
 
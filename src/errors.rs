@@ -33,3 +33,36 @@ impl ResponseError for RequestError {
         HttpResponse::InternalServerError().body(self.to_string())
     }
 }
+
+/// A query parameter that parsed fine, but whose value can't be used.
+pub struct QueryError {
+    message: String,
+}
+
+impl QueryError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.message, f)
+    }
+}
+
+impl Debug for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.message, f)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl ResponseError for QueryError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::BadRequest().body(self.to_string())
+    }
+}
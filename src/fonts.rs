@@ -0,0 +1,242 @@
+use std::sync::Arc;
+
+use font_kit::{
+    font::Font,
+    properties::{Style, Weight},
+    source::SystemSource,
+};
+use silicon::font::{FontStyle, ImageFont};
+use unicode_width::UnicodeWidthChar;
+
+use crate::errors::QueryError;
+
+/// The fonts in `assets/fonts`. The native renderer and SVG images load them from here, while
+/// silicon only finds fonts that are installed on the system besides its own copy of Hack, so
+/// the Docker image installs them as well.
+pub(crate) const BUNDLED_FONTS: &[&[u8]] = &[
+    include_bytes!("../assets/fonts/OpenSans-Regular.ttf"),
+    include_bytes!("../assets/fonts/Hack-Regular.ttf"),
+    include_bytes!("../assets/fonts/Hack-Bold.ttf"),
+    include_bytes!("../assets/fonts/Hack-Italic.ttf"),
+    include_bytes!("../assets/fonts/Hack-BoldItalic.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSansMono.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSansMono-Bold.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSansMono-Oblique.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSansMono-BoldOblique.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSerif.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSerif-Bold.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSerif-Italic.ttf"),
+    include_bytes!("../assets/fonts/DejaVuSerif-BoldItalic.ttf"),
+];
+
+pub(crate) const DEFAULT_FONT: &str = "Hack";
+
+/// The families that are consulted for characters such as CJK and emoji, which monospace fonts
+/// usually lack. The Docker image installs them.
+pub(crate) const DEFAULT_FALLBACK_FONTS: &str = "Noto Sans Mono CJK SC,Noto Color Emoji";
+
+/// Characters that take up two columns, used to measure how wide a family draws them.
+const WIDE_CHARS: &[char] = &['国', '😀'];
+
+/// A font family that silicon can draw code with.
+#[derive(Debug, Clone)]
+pub(crate) struct FontFamily {
+    pub(crate) name: String,
+    /// The advance of a column, relative to the font size.
+    advance: f32,
+    /// The advance of a character that takes up two columns, relative to the font size.
+    wide_advance: Option<f32>,
+    /// Whether silicon can load the family, which it can't for bundled families that aren't
+    /// installed on the system.
+    installed: bool,
+}
+
+impl FontFamily {
+    fn load(name: &str) -> Option<Self> {
+        let font = ImageFont::new(name, 1.0).ok()?;
+        // Silicon panics on families without a regular style.
+        let regular = font.fonts.get(&FontStyle::REGULAR)?;
+        Self::measure(name.to_owned(), regular, true)
+    }
+
+    /// Loads a bundled family from its regular face.
+    fn load_bundled(data: &[u8]) -> Option<Self> {
+        let font = Font::from_bytes(Arc::new(data.to_vec()), 0).ok()?;
+        let properties = font.properties();
+        if properties.weight != Weight::NORMAL || properties.style != Style::Normal {
+            return None;
+        }
+        Self::measure(font.family_name(), &font, false)
+    }
+
+    fn measure(name: String, regular: &Font, installed: bool) -> Option<Self> {
+        Some(Self {
+            name,
+            advance: char_advance(regular, '0')?,
+            wide_advance: WIDE_CHARS.iter().find_map(|c| char_advance(regular, *c)),
+            installed,
+        })
+    }
+}
+
+fn char_advance(font: &Font, c: char) -> Option<f32> {
+    let advance = font.advance(font.glyph_for_char(c)?).ok()?.x();
+    Some(advance / font.metrics().units_per_em as f32)
+}
+
+/// The font families that code can be drawn with. Every installed family is loaded once up front,
+/// to weed out the ones that silicon can't use, and the bundled families are added to them.
+pub(crate) fn available_families() -> Vec<FontFamily> {
+    let mut names = SystemSource::new().all_families().unwrap_or_default();
+    names.push(DEFAULT_FONT.to_owned());
+    names.sort_unstable();
+    names.dedup();
+    let mut families = names
+        .iter()
+        .filter_map(|name| FontFamily::load(name))
+        .collect::<Vec<_>>();
+    for bundled in BUNDLED_FONTS
+        .iter()
+        .filter_map(|data| FontFamily::load_bundled(data))
+    {
+        if find_family(&families, &bundled.name).is_none() {
+            families.push(bundled);
+        }
+    }
+    families.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    families
+}
+
+/// Whether silicon can load every family in `chain`.
+pub(crate) fn installed(available: &[FontFamily], chain: &[(&str, f32)]) -> bool {
+    chain
+        .iter()
+        .all(|(name, _)| find_family(available, name).is_some_and(|family| family.installed))
+}
+
+fn find_family<'a>(available: &'a [FontFamily], name: &str) -> Option<&'a FontFamily> {
+    available
+        .iter()
+        .find(|family| family.name.eq_ignore_ascii_case(name))
+}
+
+/// Parses a comma-separated list of families, leaving out the ones that aren't available.
+/// Names are matched ignoring case.
+pub(crate) fn parse_font_list<'a>(available: &'a [FontFamily], fonts: &str) -> Vec<&'a FontFamily> {
+    fonts
+        .split(',')
+        .map(str::trim)
+        .filter_map(|font| find_family(available, font))
+        .collect()
+}
+
+/// The families in a comma-separated list that aren't available.
+pub(crate) fn missing_families<'a>(available: &[FontFamily], fonts: &'a str) -> Vec<&'a str> {
+    fonts
+        .split(',')
+        .map(str::trim)
+        .filter(|font| !font.is_empty() && find_family(available, font).is_none())
+        .collect()
+}
+
+/// Parses `?font=`, a fallback chain of families where each one is used for the characters that
+/// the ones before it lack.
+///
+/// When `text` has characters that take up two columns, the `fallbacks` are added to the end of
+/// the chain. Each one gets a scale for the font size that makes those characters exactly two
+/// columns of the first family wide, so that the columns stay aligned.
+pub(crate) fn resolve_font_chain<'a>(
+    available: &'a [FontFamily],
+    fallbacks: &'a [FontFamily],
+    fonts: &str,
+    text: &str,
+) -> Result<Vec<(&'a str, f32)>, QueryError> {
+    let requested = parse_font_list(available, fonts);
+    let Some(primary) = requested.first() else {
+        return Err(QueryError::new(format!(
+            "None of the fonts in `{}` are available, see /api/fonts for the ones that are",
+            fonts
+        )));
+    };
+
+    let mut chain = requested
+        .iter()
+        .map(|family| (family.name.as_str(), 1.0))
+        .collect::<Vec<_>>();
+    if text.chars().any(|c| c.width() == Some(2)) {
+        for fallback in fallbacks {
+            if chain.iter().any(|(name, _)| *name == fallback.name) {
+                continue;
+            }
+            if let Some(wide_advance) = fallback.wide_advance {
+                chain.push((&fallback.name, 2.0 * primary.advance / wide_advance));
+            }
+        }
+    }
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn family(name: &str, advance: f32, wide_advance: Option<f32>) -> FontFamily {
+        FontFamily {
+            name: name.to_owned(),
+            advance,
+            wide_advance,
+            installed: true,
+        }
+    }
+
+    #[test]
+    fn resolves_font_chains() {
+        let available = [
+            family("Hack", 0.6, None),
+            family("Mono CJK", 0.5, Some(1.0)),
+            family("Emoji", 0.5, Some(1.5)),
+            family("Narrow", 0.5, None),
+        ];
+        let fallbacks = [
+            available[1].clone(),
+            available[2].clone(),
+            available[3].clone(),
+        ];
+
+        let chain = |fonts, text| resolve_font_chain(&available, &fallbacks, fonts, text);
+        assert_eq!(chain("hack", "ascii").unwrap(), [("Hack", 1.0)]);
+        assert_eq!(
+            chain(" Nope , hack,emoji", "ascii").unwrap(),
+            [("Hack", 1.0), ("Emoji", 1.0)]
+        );
+        // Wide characters bring in the fallbacks that have any, scaled to two columns of Hack.
+        assert_eq!(
+            chain("Hack", "// 中文").unwrap(),
+            [("Hack", 1.0), ("Mono CJK", 1.2), ("Emoji", 0.8)]
+        );
+        // Fallbacks that are already in the chain keep their place and scale.
+        assert_eq!(
+            chain("Hack,Emoji", "😀").unwrap(),
+            [("Hack", 1.0), ("Emoji", 1.0), ("Mono CJK", 1.2)]
+        );
+        assert!(chain("Nope", "ascii").is_err());
+        assert!(chain("", "ascii").is_err());
+
+        assert_eq!(
+            missing_families(&available, "Noto Sans CJK, hack,,Emoji "),
+            ["Noto Sans CJK"]
+        );
+    }
+
+    #[test]
+    fn accepts_every_bundled_family() {
+        let available = available_families();
+        for data in BUNDLED_FONTS {
+            let family = Font::from_bytes(Arc::new(data.to_vec()), 0)
+                .unwrap()
+                .family_name();
+            let chain = resolve_font_chain(&available, &[], &family, "fn main() {}").unwrap();
+            assert_eq!(chain, [(family.as_str(), 1.0)]);
+        }
+    }
+}
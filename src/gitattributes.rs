@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+
+use crate::{
+    routes::SrcPath,
+    utils::{parse_raw_gitattributes_uri, read_body},
+};
+
+/// How long a repository's parsed `.gitattributes` is reused before it is fetched again.
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+const CACHE_MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttributeValue {
+    Set,
+    Unset,
+    Unspecified,
+    Value(String),
+}
+
+impl AttributeValue {
+    fn is_true(&self) -> Option<bool> {
+        match self {
+            AttributeValue::Set => Some(true),
+            AttributeValue::Unset => Some(false),
+            AttributeValue::Unspecified => None,
+            AttributeValue::Value(value) => Some(value != "false"),
+        }
+    }
+}
+
+struct Rule {
+    pattern: Regex,
+    attributes: Vec<(String, AttributeValue)>,
+}
+
+/// The linguist attributes that apply to a single file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FileAttributes {
+    pub(crate) language: Option<String>,
+    pub(crate) generated: bool,
+    pub(crate) vendored: bool,
+}
+
+/// A parsed `.gitattributes` file from the root of a repository.
+///
+/// Only the attributes that GitHub's linguist understands are of interest to us, and nested
+/// `.gitattributes` files and `[attr]` macros are ignored.
+pub(crate) struct GitAttributes {
+    rules: Vec<Rule>,
+}
+
+impl GitAttributes {
+    pub(crate) fn parse(text: &str) -> Self {
+        let rules = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| {
+                !line.is_empty() && !line.starts_with('#') && !line.starts_with("[attr]")
+            })
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                let pattern = pattern_to_regex(parts.next()?)?;
+                let attributes = parts
+                    .map(|attribute| {
+                        if let Some(name) = attribute.strip_prefix('-') {
+                            (name.to_owned(), AttributeValue::Unset)
+                        } else if let Some(name) = attribute.strip_prefix('!') {
+                            (name.to_owned(), AttributeValue::Unspecified)
+                        } else if let Some((name, value)) = attribute.split_once('=') {
+                            (name.to_owned(), AttributeValue::Value(value.to_owned()))
+                        } else {
+                            (attribute.to_owned(), AttributeValue::Set)
+                        }
+                    })
+                    .collect();
+                Some(Rule {
+                    pattern,
+                    attributes,
+                })
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// Returns the value of `name` for `path`. Like git, later lines override earlier ones.
+    fn get(&self, path: &str, name: &str) -> Option<&AttributeValue> {
+        self.rules
+            .iter()
+            .rev()
+            .filter(|rule| rule.pattern.is_match(path))
+            .find_map(|rule| {
+                rule.attributes
+                    .iter()
+                    .rev()
+                    .find(|(attribute, _)| attribute == name)
+                    .map(|(_, value)| value)
+            })
+    }
+
+    pub(crate) fn lookup(&self, path: &str) -> FileAttributes {
+        let language = match self.get(path, "linguist-language") {
+            Some(AttributeValue::Value(language)) => Some(language.clone()),
+            _ => None,
+        };
+        FileAttributes {
+            language,
+            generated: self
+                .get(path, "linguist-generated")
+                .and_then(AttributeValue::is_true)
+                .unwrap_or(false),
+            vendored: self
+                .get(path, "linguist-vendored")
+                .and_then(AttributeValue::is_true)
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Converts a gitattributes pattern into a regex matching paths relative to the repository root.
+///
+/// Patterns without a slash match the file name at any depth, otherwise they are anchored to the
+/// root. Patterns ending in a slash only match directories, so they never match a file.
+fn pattern_to_regex(pattern: &str) -> Option<Regex> {
+    if pattern.ends_with('/') {
+        return None;
+    }
+    let anchored = pattern.contains('/');
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+    let mut regex = String::from(if anchored { "^" } else { "(?:^|/)" });
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut class = String::from("[");
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    class.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+                class.push(']');
+                regex.push_str(&class);
+            }
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    regex.push_str(&regex::escape(&escaped.to_string()));
+                }
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+
+    Regex::new(&regex).ok()
+}
+
+type CacheEntry = (Instant, Option<Arc<GitAttributes>>);
+
+/// Caches the `.gitattributes` of each repository and ref, including repositories without one.
+#[derive(Default)]
+pub(crate) struct GitAttributesCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl GitAttributesCache {
+    pub(crate) async fn get(
+        &self,
+        path: &SrcPath,
+        max_download_bytes: u32,
+    ) -> Option<Arc<GitAttributes>> {
+        let key = format!("{}/{}@{}", path.author, path.repository, path.branch);
+
+        if let Some((fetched_at, attributes)) = self.entries.lock().unwrap().get(&key) {
+            if fetched_at.elapsed() < CACHE_TTL {
+                return attributes.clone();
+            }
+        }
+
+        let attributes = Self::fetch(path, max_download_bytes).await.map(Arc::new);
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CACHE_MAX_ENTRIES {
+            entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < CACHE_TTL);
+            if entries.len() >= CACHE_MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (Instant::now(), attributes.clone()));
+
+        attributes
+    }
+
+    /// Convenience wrapper around [`GitAttributesCache::get`] and [`GitAttributes::lookup`].
+    pub(crate) async fn lookup(&self, path: &SrcPath, max_download_bytes: u32) -> FileAttributes {
+        self.get(path, max_download_bytes)
+            .await
+            .map(|attributes| attributes.lookup(&path.path))
+            .unwrap_or_default()
+    }
+
+    async fn fetch(path: &SrcPath, max_download_bytes: u32) -> Option<GitAttributes> {
+        let uri = parse_raw_gitattributes_uri(path).ok()?;
+        let response = reqwest::get(uri.to_string()).await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        let body = read_body(response.bytes_stream(), max_download_bytes).await;
+        Some(GitAttributes::parse(&String::from_utf8_lossy(&body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        pattern_to_regex(pattern).is_some_and(|regex| regex.is_match(path))
+    }
+
+    #[test]
+    fn matches_file_names_at_any_depth() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", "src/deep/main.rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+        assert!(matches("Makefile", "build/Makefile"));
+        assert!(matches("file?.txt", "a/file1.txt"));
+        assert!(!matches("file?.txt", "file10.txt"));
+    }
+
+    #[test]
+    fn anchors_patterns_with_slashes() {
+        assert!(matches("/vendor.js", "vendor.js"));
+        assert!(!matches("/vendor.js", "lib/vendor.js"));
+        assert!(matches("lib/*.js", "lib/a.js"));
+        assert!(!matches("lib/*.js", "lib/sub/a.js"));
+        assert!(!matches("lib/*.js", "src/lib/a.js"));
+    }
+
+    #[test]
+    fn matches_double_stars() {
+        assert!(matches("vendor/**", "vendor/a/b.js"));
+        assert!(matches("**/generated/*.go", "generated/a.go"));
+        assert!(matches("**/generated/*.go", "x/y/generated/a.go"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+    }
+
+    #[test]
+    fn matches_classes_and_escapes() {
+        assert!(matches("*.[ch]", "x.c"));
+        assert!(!matches("*.[ch]", "x.o"));
+        assert!(matches("*.[!ch]", "x.o"));
+        assert!(matches("\\*.txt", "*.txt"));
+        assert!(!matches("\\*.txt", "a.txt"));
+        assert!(matches("a.b", "a.b"));
+        assert!(!matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn skips_directories_and_invalid_patterns() {
+        assert!(pattern_to_regex("docs/").is_none());
+        assert!(pattern_to_regex("[]").is_none());
+    }
+
+    #[test]
+    fn later_lines_override_earlier_ones() {
+        let attributes = GitAttributes::parse(
+            "# comment\n\
+             [attr]binary -diff\n\
+             *.h linguist-language=C++\n\
+             legacy/*.h linguist-language=C linguist-vendored\n\
+             legacy/keep.h -linguist-vendored\n\
+             dist/** linguist-generated=true\n\
+             dist/src/** linguist-generated=false\n",
+        );
+
+        let header = attributes.lookup("include/a.h");
+        assert_eq!(header.language.as_deref(), Some("C++"));
+        assert!(!header.vendored);
+
+        let legacy = attributes.lookup("legacy/a.h");
+        assert_eq!(legacy.language.as_deref(), Some("C"));
+        assert!(legacy.vendored);
+        assert!(!attributes.lookup("legacy/keep.h").vendored);
+
+        assert!(attributes.lookup("dist/app.js").generated);
+        assert!(!attributes.lookup("dist/src/app.js").generated);
+        assert!(attributes.lookup("README.md").language.is_none());
+    }
+}
@@ -1,41 +1,494 @@
-use image::DynamicImage;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::Cursor,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{rt::time, web};
+use image::{
+    imageops, imageops::FilterType, DynamicImage, ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use maud::html;
+use oxipng::StripChunks;
 use resvg::{
     self, tiny_skia,
-    usvg::{self, fontdb, TreeParsing, TreeTextToPath},
+    usvg::{self, fontdb, ImageHrefResolver, TreeParsing, TreeTextToPath},
+};
+use serde::Deserialize;
+use silicon::{
+    assets::HighlightingAssets,
+    font::{FontCollection, FontStyle as TextStyle},
+    formatter::ImageFormatterBuilder,
+    utils::{Background, ShadowAdder, ToRgba},
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Color, FontStyle, Style, Theme},
+    util::LinesWithEndings,
+    LoadingError,
 };
-use silicon::{assets::HighlightingAssets, formatter::ImageFormatterBuilder, utils::ShadowAdder};
-use syntect::{easy::HighlightLines, util::LinesWithEndings};
+use unicode_width::UnicodeWidthChar;
 
-use crate::routes::ImgQuery;
+use crate::{
+    errors::QueryError,
+    fonts::{self, FontFamily},
+    renderer::{self, Frame, NativeRenderer, SvgText, SvgWindow},
+    routes::ImgQuery,
+    syntax,
+    utils::{check_query_range, DirectoryCache, Lines, QueryHighlight},
+};
 
 pub(crate) struct TextImageGenerator {
     ha: HighlightingAssets,
+    /// The font families that silicon can load.
+    fonts: Vec<FontFamily>,
+    /// The families that are added to the end of every font chain when they're needed.
+    fallback_fonts: Vec<FontFamily>,
+    /// The widest image we'll render, lines longer than fit are truncated.
+    max_width: u32,
+    /// The most pixels in an image, which lowers `?scale=` for images that would be bigger.
+    max_pixels: u64,
+    native: NativeRenderer,
 }
 
-impl Default for TextImageGenerator {
-    fn default() -> Self {
-        Self {
-            ha: HighlightingAssets::new(),
+impl TextImageGenerator {
+    /// `assets_dir` is searched for `.tmTheme` and `.sublime-syntax` files, which are added to
+    /// the ones bundled with silicon. `fallback_fonts` is a comma-separated list of families.
+    pub(crate) fn new(
+        max_width: u32,
+        max_pixels: u64,
+        assets_dir: Option<&str>,
+        fallback_fonts: &str,
+    ) -> Result<Self, LoadingError> {
+        let mut ha = HighlightingAssets::new();
+        let mut builder = ha.syntax_set.into_builder();
+        if let Some(assets_dir) = assets_dir {
+            builder.add_from_folder(assets_dir, true)?;
+            ha.theme_set.add_from_folder(assets_dir)?;
+        }
+        // Silicon's bundled syntax set has no "Plain Text" syntax, which we fall back to when
+        // nothing else matches.
+        if !builder
+            .syntaxes()
+            .iter()
+            .any(|syntax| syntax.name == "Plain Text")
+        {
+            builder.add_plain_text_syntax();
+        }
+        ha.syntax_set = builder.build();
+
+        let fonts = fonts::available_families();
+        for missing in fonts::missing_families(&fonts, fallback_fonts) {
+            eprintln!(
+                "The fallback font `{}` isn't installed, so characters that only it has will be \
+                 missing from code images",
+                missing
+            );
+        }
+        let fallback_fonts = fonts::parse_font_list(&fonts, fallback_fonts)
+            .into_iter()
+            .cloned()
+            .collect();
+
+        Ok(Self {
+            ha,
+            fonts,
+            fallback_fonts,
+            max_width,
+            max_pixels,
+            native: NativeRenderer::default(),
+        })
+    }
+
+    pub(crate) fn theme_names(&self) -> Vec<&str> {
+        self.ha
+            .theme_set
+            .themes
+            .keys()
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub(crate) fn font_names(&self) -> Vec<&str> {
+        self.fonts.iter().map(|font| font.name.as_str()).collect()
+    }
+
+    /// Looks up a theme by name, ignoring case if there's no exact match.
+    fn find_theme(&self, name: &str) -> Result<&Theme, QueryError> {
+        let themes = &self.ha.theme_set.themes;
+        themes
+            .get(name)
+            .or_else(|| {
+                themes
+                    .iter()
+                    .find(|(theme, _)| theme.eq_ignore_ascii_case(name))
+                    .map(|(_, theme)| theme)
+            })
+            .ok_or_else(|| {
+                QueryError::new(format!(
+                    "Unknown theme `{}`, the available themes are: {}",
+                    name,
+                    self.theme_names().join(", ")
+                ))
+            })
+    }
+}
+
+const DEFAULT_FONT_SIZE: f32 = 26.0;
+const MIN_FONT_SIZE: f32 = 6.0;
+const MAX_FONT_SIZE: f32 = 128.0;
+const DEFAULT_TAB_WIDTH: u8 = 4;
+const MAX_TAB_WIDTH: u8 = 16;
+const MIN_COLUMNS: usize = 8;
+/// The padding silicon adds between the code and the edge of the window.
+const CODE_PADDING: u32 = 25;
+const DEFAULT_PAD_X: u32 = 80;
+const DEFAULT_PAD_Y: u32 = 100;
+const MAX_PAD: u32 = 400;
+const DEFAULT_SHADOW_BLUR: f32 = 50.0;
+const MAX_SHADOW_BLUR: f32 = 100.0;
+const DEFAULT_LINE_PAD: u32 = 2;
+const MAX_LINE_PAD: u32 = 40;
+const MAX_SCALE: u32 = 3;
+const MAX_TITLE_CHARS: usize = 200;
+const TITLE_FONT_SIZE: f32 = 20.0;
+/// The vertical center of silicon's window controls, which the title is aligned with.
+const TITLE_BAR_CENTER: u32 = 35;
+/// The space taken by silicon's window controls, from the left edge of the window.
+const WINDOW_CONTROLS_WIDTH: u32 = 150;
+/// The height of a line in a typical monospace font, relative to the font size.
+const MONOSPACE_LINE_HEIGHT: f32 = 1.2;
+/// The height of the title bar, from the top edge of the window to the code padding.
+const TITLE_BAR_HEIGHT: u32 = 50;
+/// The width of SVG files rendered at a scale of 1.
+const SVG_WIDTH: u32 = 960;
+/// The family of SVG text without a family, and of the generic families that nothing better is
+/// loaded for.
+const SVG_SANS_SERIF: &str = "Open Sans";
+const SVG_SERIF: &str = "DejaVu Serif";
+/// The loaded families that SVG's `cursive` maps to, in order of preference. None of them are
+/// bundled.
+const SVG_CURSIVE_FAMILIES: &[&str] = &["Comic Neue", "Comic Sans MS", "URW Chancery L"];
+/// The size of a square of `?bg=checkerboard`, at a scale of 1.
+const CHECKERBOARD_SQUARE: u32 = 8;
+const CHECKERBOARD_GREY: u8 = 204;
+/// The colors of silicon's shadow, which SVG output copies.
+const SHADOW_BACKGROUND: Color = Color {
+    r: 0xAB,
+    g: 0xB8,
+    b: 0xC3,
+    a: 0xFF,
+};
+const SHADOW_COLOR: Color = Color {
+    r: 0x70,
+    g: 0x70,
+    b: 0x70,
+    a: 0xFF,
+};
+
+/// What to do with lines that are longer than a number of columns.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Overflow {
+    /// Continue the line on the next row.
+    Wrap(usize),
+    /// Cut the line off with an ellipsis.
+    Truncate(usize),
+}
+
+/// How a code image is laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Fit {
+    /// As big as the code, with silicon's shadow around it.
+    #[default]
+    Natural,
+    /// Centered on a 1.91:1 canvas, which is what Open Graph consumers like Discord crop to.
+    Og,
+    /// Centered on a 2:1 canvas, which is what Twitter crops `summary_large_image` cards to.
+    Twitter,
+}
+
+impl Fit {
+    /// `None` for natural sizes, and for sizes that don't fit in a `u32`.
+    pub(crate) fn canvas_size(self, scale: u32) -> Option<(u32, u32)> {
+        let (width, height) = match self {
+            Fit::Natural => return None,
+            Fit::Og => (1200_u32, 630_u32),
+            Fit::Twitter => (1200, 600),
+        };
+        Some((width.checked_mul(scale)?, height.checked_mul(scale)?))
+    }
+}
+
+/// Checks `?scale=` and picks the renderer for it, since silicon can only draw at a scale of 1.
+fn resolve_scale(query: &ImgQuery) -> Result<(u32, Renderer), QueryError> {
+    let scale = check_query_range("scale", query.scale, 1, MAX_SCALE)?.unwrap_or(1);
+    let renderer = match (query.renderer, scale) {
+        (Some(Renderer::Silicon), 2..) => {
+            return Err(QueryError::new("`scale` only works with `renderer=native`"));
+        }
+        (Some(renderer), _) => renderer,
+        (None, 1) => Renderer::Silicon,
+        (None, _) => Renderer::Native,
+    };
+    Ok((scale, renderer))
+}
+
+/// Checks that lines are either wrapped or truncated, not both.
+fn resolve_overflow(query: &ImgQuery) -> Result<Option<Overflow>, QueryError> {
+    match (query.wrap, query.truncate) {
+        (Some(_), Some(_)) => Err(QueryError::new(
+            "`wrap` and `truncate` can't be used together",
+        )),
+        (wrap, truncate) => Ok(wrap
+            .map(Overflow::Wrap)
+            .or(truncate.map(Overflow::Truncate))),
+    }
+}
+
+/// The size of a code image in `format`, for `og:image:width` and `og:image:height`. Only images
+/// fitted on a canvas have a size that's known up front, and raster images only at a scale of 1,
+/// since the pixel budget can lower bigger scales depending on the code.
+pub(crate) fn code_image_size(
+    query: &ImgQuery,
+    format: Format,
+) -> Result<Option<(u32, u32)>, QueryError> {
+    let (scale, _) = resolve_scale(query)?;
+    Ok(match (scale, format) {
+        (1, _) | (_, Format::Svg) => query.fit.and_then(|fit| fit.canvas_size(scale)),
+        _ => None,
+    })
+}
+
+/// What draws the window with the code in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Renderer {
+    #[default]
+    Silicon,
+    /// Our own renderer, which lays out every character on a grid of columns.
+    Native,
+}
+
+/// The kind of file an image is served as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Format {
+    #[default]
+    Png,
+    /// Only for code, which is always drawn by the native renderer since silicon can only draw
+    /// pixels.
+    Svg,
+    /// Lossless, so that code stays as crisp as in a PNG.
+    Webp,
+    Jpeg,
+}
+
+impl Format {
+    /// The raster formats that can be picked with the `Accept` header, the smallest first.
+    const NEGOTIABLE: [Format; 3] = [Format::Webp, Format::Png, Format::Jpeg];
+
+    pub(crate) fn mime_type(self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Svg => "image/svg+xml",
+            Format::Webp => "image/webp",
+            Format::Jpeg => "image/jpeg",
+        }
+    }
+
+    /// The format that images other than code are served in, since only code can be drawn as an
+    /// SVG. They fall back to PNG.
+    pub(crate) fn raster(self) -> Format {
+        match self {
+            Format::Svg => Format::Png,
+            format => format,
+        }
+    }
+
+    /// Picks the raster format with the highest quality in an `Accept` header. Only formats that
+    /// are named count, since plenty of clients that send `*/*` can't decode WebP, so those get a
+    /// PNG.
+    pub(crate) fn negotiate(accept: &str) -> Format {
+        let mut best = (Format::Png, 0.0);
+        for format in Format::NEGOTIABLE {
+            let quality = accept
+                .split(',')
+                .filter_map(|range| {
+                    let mut params = range.split(';').map(str::trim);
+                    if !params.next()?.eq_ignore_ascii_case(format.mime_type()) {
+                        return None;
+                    }
+                    params
+                        .find_map(|param| param.strip_prefix("q="))
+                        .map_or(Some(1.0), |quality| quality.parse::<f32>().ok())
+                })
+                .fold(0.0, f32::max);
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+        best.0
+    }
+}
+
+const JPEG_QUALITY: u8 = 90;
+
+/// A second pass over encoded PNGs that reduces the palette and bit depth where the colors allow,
+/// compresses harder and strips metadata.
+#[derive(Debug, Clone)]
+pub(crate) struct PngOptimization {
+    /// An oxipng preset, from 0 to 6.
+    pub(crate) level: u8,
+    /// Once it's spent, the unoptimized PNG is used.
+    pub(crate) timeout: Duration,
+}
+
+impl PngOptimization {
+    pub(crate) const MAX_LEVEL: u8 = 6;
+
+    async fn optimize(&self, png: Vec<u8>) -> Vec<u8> {
+        let mut options = oxipng::Options::from_preset(self.level);
+        options.strip = StripChunks::Safe;
+        // oxipng only checks its timeout before each of its trials, so it gets a share of ours to
+        // leave the trial that's running time to finish.
+        options.timeout = Some(self.timeout * 3 / 4);
+
+        let optimized = web::block({
+            let png = png.clone();
+            move || oxipng::optimize_from_memory(&png, &options)
+        });
+        match time::timeout(self.timeout, optimized).await {
+            Ok(Ok(Ok(optimized))) => optimized,
+            // A trial that overran is left to finish on the blocking thread.
+            _ => png,
+        }
+    }
+}
+
+/// What transparent images are put on when they're encoded without transparency.
+pub(crate) const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// Encodes `image` as a raster `format` on a blocking thread, where SVG falls back to PNG. Formats
+/// without transparency put the image on `matte`.
+pub(crate) async fn encode_image(
+    image: DynamicImage,
+    format: Format,
+    matte: Rgba<u8>,
+    png_optimization: Option<&PngOptimization>,
+) -> Vec<u8> {
+    let buffer = web::block(move || encode(&image, format, matte))
+        .await
+        .unwrap();
+    match png_optimization {
+        Some(png_optimization) if matches!(format, Format::Png | Format::Svg) => {
+            png_optimization.optimize(buffer).await
         }
+        _ => buffer,
     }
 }
 
+fn encode(image: &DynamicImage, format: Format, matte: Rgba<u8>) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    match format {
+        Format::Png | Format::Svg => image
+            .write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Png)
+            .unwrap(),
+        Format::Webp => {
+            let image = image.to_rgba8();
+            buffer.extend_from_slice(
+                &webp::Encoder::from_rgba(&image, image.width(), image.height()).encode_lossless(),
+            );
+        }
+        Format::Jpeg => DynamicImage::ImageRgb8(flatten(image, matte))
+            .write_to(
+                &mut Cursor::new(&mut buffer),
+                ImageOutputFormat::Jpeg(JPEG_QUALITY),
+            )
+            .unwrap(),
+    }
+    buffer
+}
+
+/// Blends the transparent parts of `image` with `matte`, for formats without an alpha channel.
+fn flatten(image: &DynamicImage, matte: Rgba<u8>) -> RgbImage {
+    let mut flat = RgbImage::new(image.width(), image.height());
+    for (pixel, Rgba([r, g, b, a])) in flat.pixels_mut().zip(image.to_rgba8().pixels()) {
+        let alpha = *a as u32;
+        let blend = |color: u8, matte: u8| {
+            ((color as u32 * alpha + matte as u32 * (255 - alpha) + 127) / 255) as u8
+        };
+        *pixel = Rgb([
+            blend(*r, matte[0]),
+            blend(*g, matte[1]),
+            blend(*b, matte[2]),
+        ]);
+    }
+    flat
+}
+
+pub(crate) enum CodeImage {
+    /// The image, and the color to put it on in formats without transparency.
+    Raster(DynamicImage, Rgba<u8>),
+    Svg(String),
+}
+
+/// A row of a code image.
+struct Row<'a> {
+    gutter: String,
+    /// The line that the row belongs to, or `None` for rows standing in for skipped lines.
+    number: Option<u32>,
+    tokens: Vec<(Style, &'a str)>,
+}
+
+/// Everything about a code image besides the code itself.
+pub(crate) struct TextImageOptions<'a> {
+    /// The line numbers of the lines of the code.
+    pub(crate) lines: &'a Lines,
+    pub(crate) path: Option<&'a str>,
+    pub(crate) language: Option<&'a str>,
+    pub(crate) theme: &'a str,
+    /// A fallback chain of font families, each with a scale for the font size.
+    pub(crate) fonts: &'a [(&'a str, f32)],
+    pub(crate) font_size: f32,
+    /// Absolute line numbers to draw a background band behind, on top of the line picked with
+    /// `?line=`.
+    pub(crate) highlight: Option<&'a QueryHighlight>,
+    /// Dim every line that isn't highlighted.
+    pub(crate) focus: bool,
+    /// Lines are always truncated to fit in the maximum width, even without an overflow mode.
+    pub(crate) overflow: Option<Overflow>,
+    pub(crate) fit: Fit,
+    pub(crate) renderer: Renderer,
+    pub(crate) format: Format,
+    pub(crate) svg_text: SvgText,
+    /// Drawn in the title bar, which is also shown without window controls when there's a title.
+    pub(crate) title: Option<&'a str>,
+    pub(crate) window_controls: bool,
+    pub(crate) line_numbers: bool,
+    pub(crate) line_pad: u32,
+    /// The space around the window, which is ignored when fitting the window on a canvas.
+    pub(crate) pad_x: u32,
+    pub(crate) pad_y: u32,
+    /// Replaces silicon's background, or the gradient when fitting the window on a canvas.
+    pub(crate) background: Option<Rgba<u8>>,
+    pub(crate) shadow_blur: f32,
+    /// How many pixels there are to a point. The pixel budget can lower it.
+    pub(crate) scale: u32,
+}
+
 impl TextImageGenerator {
     pub(crate) fn generate(
         &self,
         code: &str,
-        starting_line: u32,
-        theme: &str,
-        font: &str,
-        font_size: f32,
-    ) -> DynamicImage {
-        let (ps, ts) = (&self.ha.syntax_set, &self.ha.theme_set);
-
-        // Change this later to first choose syntax by file extension
-        let syntax = ps
-            .find_syntax_by_first_line(code)
-            .unwrap_or(ps.find_syntax_by_token("rs").unwrap());
-        let theme = ts.themes.get(theme).unwrap_or(&ts.themes["Dracula"]);
+        options: &TextImageOptions,
+    ) -> Result<CodeImage, QueryError> {
+        let ps = &self.ha.syntax_set;
+
+        let syntax = syntax::find_syntax(ps, code, options.path, options.language);
+        let theme = self.find_theme(options.theme)?;
 
         let mut h = HighlightLines::new(syntax, theme);
         let highlight = LinesWithEndings::from(code)
@@ -43,58 +496,1385 @@ impl TextImageGenerator {
             .collect::<Result<Vec<_>, _>>()
             .unwrap();
 
-        let mut formatter = ImageFormatterBuilder::new()
-            .font(vec![(font, font_size)])
-            .shadow_adder(ShadowAdder::default())
-            .line_offset(starting_line)
-            .build()
-            .unwrap();
+        let foreground = theme.settings.foreground.unwrap_or(Color::WHITE);
+        let background = theme.settings.background.unwrap_or(Color::BLACK);
+        let gutter_style = Style {
+            foreground: Color {
+                r: foreground.r.saturating_sub(20),
+                g: foreground.g.saturating_sub(20),
+                b: foreground.b.saturating_sub(20),
+                a: foreground.a,
+            },
+            background,
+            font_style: FontStyle::empty(),
+        };
+
+        // Line numbers are drawn as part of each row rather than by silicon, so that rows can
+        // skip lines, with a "⋯" row in place of the skipped lines, and so that wrapped lines
+        // don't get a line number of their own.
+        let numbers = options.lines.numbers().collect::<Vec<_>>();
+        let width = numbers.last().unwrap_or(&1).to_string().len();
+        let gutter = |label: &dyn std::fmt::Display| {
+            if options.line_numbers {
+                format!("{:>width$} ", label)
+            } else {
+                String::new()
+            }
+        };
+
+        // Wide characters are fitted to their columns by the native renderer, so it doesn't need
+        // the scales of the fallback fonts.
+        let font_names = options
+            .fonts
+            .iter()
+            .map(|(font, _)| *font)
+            .collect::<Vec<_>>();
+        let column = self.native.column_width(&font_names, options.font_size);
+        let frame_width = 2 * CODE_PADDING + 2 * options.pad_x;
+        let max_columns = (self.max_width.saturating_sub(frame_width) as f32 / column) as usize;
+        // Wrapped rows start with a "↪ " even without line numbers.
+        let gutter_columns = match options.overflow {
+            _ if options.line_numbers => width + 1,
+            Some(Overflow::Wrap(_)) => 2,
+            _ => 0,
+        };
+        let max_columns = max_columns.saturating_sub(gutter_columns).max(MIN_COLUMNS);
+        let overflow = match options.overflow {
+            Some(Overflow::Wrap(columns)) => {
+                Overflow::Wrap(columns.clamp(MIN_COLUMNS, max_columns))
+            }
+            Some(Overflow::Truncate(columns)) => {
+                Overflow::Truncate(columns.clamp(MIN_COLUMNS, max_columns))
+            }
+            None => Overflow::Truncate(max_columns),
+        };
+
+        let mut rows: Vec<Row> = Vec::new();
+        let mut previous: Option<u32> = None;
+        for (tokens, &number) in highlight.iter().zip(numbers.iter()) {
+            if previous.is_some_and(|previous| previous + 1 != number) {
+                rows.push(Row {
+                    gutter: if options.line_numbers {
+                        gutter(&"⋯")
+                    } else {
+                        "⋯".to_owned()
+                    },
+                    number: None,
+                    tokens: Vec::new(),
+                });
+            }
+            previous = Some(number);
+
+            match overflow {
+                Overflow::Wrap(columns) => {
+                    for (i, tokens) in split_tokens(tokens, columns).into_iter().enumerate() {
+                        rows.push(Row {
+                            gutter: match i {
+                                0 => gutter(&number),
+                                _ if options.line_numbers => gutter(&"↪"),
+                                _ => "↪ ".to_owned(),
+                            },
+                            number: Some(number),
+                            tokens,
+                        });
+                    }
+                }
+                Overflow::Truncate(columns) => {
+                    let mut split = split_tokens(tokens, columns);
+                    let tokens = if split.len() > 1 {
+                        // Makes room for the "…".
+                        let mut tokens = split_tokens(tokens, columns - 1).swap_remove(0);
+                        tokens.push((gutter_style, "…"));
+                        tokens
+                    } else {
+                        split.remove(0)
+                    };
+                    rows.push(Row {
+                        gutter: gutter(&number),
+                        number: Some(number),
+                        tokens,
+                    });
+                }
+            }
+        }
+
+        let mut rows = rows
+            .iter()
+            .map(|row| {
+                let mut tokens = vec![(gutter_style, row.gutter.as_str())];
+                tokens.extend(row.tokens.iter().copied());
+                (row.number, tokens)
+            })
+            .collect::<Vec<_>>();
+
+        // Silicon counts highlighted lines from the first row of the image.
+        let highlighted_rows = rows
+            .iter()
+            .enumerate()
+            .filter(|(_, (number, _))| {
+                number.is_some_and(|number| {
+                    options
+                        .highlight
+                        .is_some_and(|lines| lines.contains(number))
+                        || options.lines.around.is_some_and(|(line, _)| line == number)
+                })
+            })
+            .map(|(row, _)| row as u32 + 1)
+            .collect::<Vec<_>>();
+
+        if options.focus && !highlighted_rows.is_empty() {
+            for (row, (_, tokens)) in rows.iter_mut().enumerate() {
+                if !highlighted_rows.contains(&(row as u32 + 1)) {
+                    for (style, _) in tokens.iter_mut() {
+                        style.foreground = dim_color(style.foreground, background);
+                    }
+                }
+            }
+        }
+        let rows = rows.into_iter().map(|(_, row)| row).collect::<Vec<_>>();
+
+        let scale = if options.format == Format::Svg {
+            options.scale
+        } else {
+            let columns = rows
+                .iter()
+                .map(|tokens| {
+                    tokens
+                        .iter()
+                        .flat_map(|(_, text)| text.chars())
+                        .filter_map(UnicodeWidthChar::width)
+                        .sum::<usize>()
+                })
+                .max()
+                .unwrap_or(0);
+            let window = (
+                columns as f32 * column + (2 * CODE_PADDING) as f32,
+                rows.len() as f32
+                    * (options.font_size * MONOSPACE_LINE_HEIGHT + options.line_pad as f32)
+                    + (2 * CODE_PADDING + TITLE_BAR_HEIGHT) as f32,
+            );
+            let size = match options.fit.canvas_size(1) {
+                Some((width, height)) => (window.0.max(width as f32), window.1.max(height as f32)),
+                None => (
+                    window.0 + (2 * options.pad_x) as f32,
+                    window.1 + (2 * options.pad_y) as f32,
+                ),
+            };
+            fit_pixel_budget(size, options.scale, self.max_pixels)?
+        };
+
+        let frame = Frame {
+            fonts: &font_names,
+            font_size: options.font_size,
+            line_pad: options.line_pad,
+            title: options.title,
+            window_controls: options.window_controls,
+            highlighted_rows: &highlighted_rows,
+        };
+        if options.format == Format::Svg {
+            let window = self
+                .native
+                .render_svg(&rows, theme, &frame, options.svg_text);
+            return Ok(CodeImage::Svg(svg_document(
+                &window, options, background, foreground,
+            )));
+        }
+
+        let image = match options.renderer {
+            Renderer::Silicon => {
+                let mut formatter = ImageFormatterBuilder::new()
+                    .font(
+                        options
+                            .fonts
+                            .iter()
+                            .map(|(font, scale)| (*font, options.font_size * scale))
+                            .collect(),
+                    )
+                    .line_number(false)
+                    .line_pad(options.line_pad)
+                    .window_controls(options.window_controls || options.title.is_some())
+                    .highlight_lines(highlighted_rows.clone())
+                    .build()
+                    .map_err(|error| QueryError::new(error.to_string()))?;
 
-        formatter.format(&highlight, theme)
+                let mut image = formatter.format(&rows, theme);
+                // Silicon lays out fallback fonts with their own advances, which can make the
+                // window wider than the columns allow for.
+                let max_window_width = self.max_width.saturating_sub(2 * options.pad_x);
+                if image.width() > max_window_width {
+                    image = image.resize(max_window_width, image.height(), FilterType::Lanczos3);
+                }
+                if let Some(title) = options.title {
+                    draw_title(
+                        &mut image,
+                        title,
+                        options.fonts,
+                        gutter_style.foreground,
+                        background,
+                        options.window_controls,
+                    );
+                }
+                image
+            }
+            Renderer::Native => self.native.render(&rows, theme, &frame, scale as f32)?,
+        };
+
+        // A transparent `?bg` shows the theme's background in formats without transparency.
+        let matte = options
+            .background
+            .filter(|color| color[3] == u8::MAX)
+            .unwrap_or_else(|| {
+                let Rgba([r, g, b, _]) = background.to_rgba();
+                Rgba([r, g, b, u8::MAX])
+            });
+        let shadow_blur = options.shadow_blur * scale as f32;
+        let image = match options.fit.canvas_size(scale) {
+            Some((width, height)) => {
+                let canvas = options.background.map_or_else(
+                    || gradient(width, height, background, foreground),
+                    |color| RgbaImage::from_pixel(width, height, color),
+                );
+                fit_to_canvas(&image, canvas, shadow_blur)
+            }
+            None => ShadowAdder::new()
+                .background(
+                    options
+                        .background
+                        .map_or_else(Background::default, Background::Solid),
+                )
+                .blur_radius(shadow_blur)
+                .pad_horiz(options.pad_x * scale)
+                .pad_vert(options.pad_y * scale)
+                .apply_to(&image),
+        };
+        Ok(CodeImage::Raster(image, matte))
     }
-    pub(crate) fn generate_from_query(&self, code: &str, query: &ImgQuery) -> DynamicImage {
-        self.generate(
+
+    /// `language` is a hint such as a `linguist-language` attribute, which `?lang=` overrides.
+    pub(crate) fn generate_from_query(
+        &self,
+        code: &str,
+        lines: &Lines,
+        path: Option<&str>,
+        language: Option<&str>,
+        query: &ImgQuery,
+    ) -> Result<CodeImage, QueryError> {
+        let code = expand_tabs(
             code,
-            query.lines.map(|lines| lines.from).unwrap_or(1),
-            &query.theme.clone().unwrap_or("Dracula".to_owned()),
-            &query.font.clone().unwrap_or("Hack".to_owned()),
-            query.font_size.unwrap_or(26.0),
+            query
+                .tab_width
+                .unwrap_or(DEFAULT_TAB_WIDTH)
+                .clamp(1, MAX_TAB_WIDTH) as usize,
+        );
+        let code = if query.dedent.unwrap_or(true) {
+            dedent(&code)
+        } else {
+            code
+        };
+
+        let title = match &query.title {
+            Some(title) if title.chars().count() > MAX_TITLE_CHARS => {
+                return Err(QueryError::new(format!(
+                    "`title` must be at most {} characters long",
+                    MAX_TITLE_CHARS
+                )));
+            }
+            Some(title) => Some(title.as_str()).filter(|title| !title.is_empty()),
+            None => path
+                .and_then(|path| path.rsplit('/').next())
+                .filter(|file_name| !file_name.is_empty()),
+        };
+        let background = query.bg.as_deref().map(parse_bg_color).transpose()?;
+        let (scale, renderer) = resolve_scale(query)?;
+        let fonts = fonts::resolve_font_chain(
+            &self.fonts,
+            &self.fallback_fonts,
+            query.font.as_deref().unwrap_or(fonts::DEFAULT_FONT),
+            &format!("{}{}", title.unwrap_or(""), code),
+        )?;
+        // Bundled families that aren't installed on the system can only be drawn natively.
+        let renderer = if fonts::installed(&self.fonts, &fonts) {
+            renderer
+        } else {
+            Renderer::Native
+        };
+
+        self.generate(
+            &code,
+            &TextImageOptions {
+                lines,
+                path,
+                language: query.lang.as_deref().or(language),
+                theme: query.theme.as_deref().unwrap_or("Dracula"),
+                fonts: &fonts,
+                font_size: check_query_range(
+                    "font_size",
+                    query.font_size,
+                    MIN_FONT_SIZE,
+                    MAX_FONT_SIZE,
+                )?
+                .unwrap_or(DEFAULT_FONT_SIZE),
+                highlight: query.highlight.as_ref(),
+                focus: query.focus.unwrap_or(false),
+                overflow: resolve_overflow(query)?,
+                fit: query.fit.unwrap_or_default(),
+                renderer,
+                format: query.format.unwrap_or_default(),
+                svg_text: query.svg_text.unwrap_or_default(),
+                title,
+                window_controls: query.window_controls.unwrap_or(true),
+                line_numbers: query.line_numbers.unwrap_or(true),
+                line_pad: check_query_range("line_pad", query.line_pad, 0, MAX_LINE_PAD)?
+                    .unwrap_or(DEFAULT_LINE_PAD),
+                pad_x: check_query_range("pad_x", query.pad_x, 0, MAX_PAD)?
+                    .unwrap_or(DEFAULT_PAD_X),
+                pad_y: check_query_range("pad_y", query.pad_y, 0, MAX_PAD)?
+                    .unwrap_or(DEFAULT_PAD_Y),
+                background,
+                shadow_blur: check_query_range(
+                    "shadow_blur",
+                    query.shadow_blur,
+                    0.0,
+                    MAX_SHADOW_BLUR,
+                )?
+                .unwrap_or(DEFAULT_SHADOW_BLUR),
+                scale,
+            },
         )
     }
 }
 
-pub(crate) struct SvgImageGenerator {
-    db: fontdb::Database,
+/// Splits a highlighted line, without its line ending, into rows of at most `columns` columns.
+/// Wide characters such as CJK take up two columns.
+fn split_tokens<'a>(tokens: &[(Style, &'a str)], columns: usize) -> Vec<Vec<(Style, &'a str)>> {
+    let mut rows = vec![Vec::new()];
+    let mut column = 0;
+    for &(style, text) in tokens {
+        let mut text = text.trim_end_matches(['\r', '\n']);
+        while !text.is_empty() {
+            let mut split = text.len();
+            for (i, c) in text.char_indices() {
+                let width = c.width().unwrap_or(0);
+                // A character wider than a whole row still has to go somewhere.
+                if column + width > columns && (i > 0 || column > 0) {
+                    split = i;
+                    break;
+                }
+                column += width;
+            }
+            if split == 0 {
+                rows.push(Vec::new());
+                column = 0;
+                continue;
+            }
+            let (head, tail) = text.split_at(split);
+            rows.last_mut().unwrap().push((style, head));
+            text = tail;
+        }
+    }
+    rows
 }
 
-impl Default for SvgImageGenerator {
-    fn default() -> Self {
-        let mut db = fontdb::Database::new();
-        db.load_font_data(include_bytes!("../assets/fonts/OpenSans-Regular.ttf").to_vec());
-        db.set_serif_family("Open Sans".to_string());
-        Self { db }
+/// Replaces tabs with spaces up to the next multiple of `tab_width` columns, where wide characters
+/// such as CJK take up two columns.
+fn expand_tabs(code: &str, tab_width: usize) -> String {
+    let mut expanded = String::with_capacity(code.len());
+    let mut column = 0;
+    for c in code.chars() {
+        match c {
+            '\t' => {
+                let spaces = tab_width - column % tab_width;
+                expanded.extend(std::iter::repeat_n(' ', spaces));
+                column += spaces;
+            }
+            '\n' => {
+                expanded.push(c);
+                column = 0;
+            }
+            _ => {
+                expanded.push(c);
+                column += c.width().unwrap_or(0);
+            }
+        }
     }
+    expanded
+}
+
+/// Removes the indentation that every non-blank line has in common.
+fn dedent(code: &str) -> String {
+    let indent = code
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches(' ').len())
+        .min()
+        .unwrap_or(0);
+    if indent == 0 {
+        return code.to_owned();
+    }
+
+    LinesWithEndings::from(code)
+        .map(|line| {
+            let line_indent = line.len() - line.trim_start_matches(' ').len();
+            &line[indent.min(line_indent)..]
+        })
+        .collect()
+}
+
+/// Mixes `amount` of `other` into `color`.
+fn mix_colors(color: Color, other: Color, amount: f32) -> Color {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount).round() as u8;
+    Color {
+        r: mix(color.r, other.r),
+        g: mix(color.g, other.g),
+        b: mix(color.b, other.b),
+        a: color.a,
+    }
+}
+
+/// Blends `color` most of the way into `background`, for lines that are out of focus.
+fn dim_color(color: Color, background: Color) -> Color {
+    mix_colors(color, background, 0.6)
+}
+
+/// Draws `title` centered in the title bar, or right after the window controls when the window is
+/// too narrow to center it. The window controls are covered up when they're hidden.
+fn draw_title(
+    image: &mut DynamicImage,
+    title: &str,
+    fonts: &[(&str, f32)],
+    color: Color,
+    background: Color,
+    window_controls: bool,
+) {
+    if !window_controls {
+        let (background, pixels) = (background.to_rgba(), image.as_mut_rgba8().unwrap());
+        for y in 0..TITLE_BAR_CENTER * 2 {
+            for x in CODE_PADDING / 2..WINDOW_CONTROLS_WIDTH.min(pixels.width() - CODE_PADDING / 2)
+            {
+                pixels.put_pixel(x, y, background);
+            }
+        }
+    }
+
+    let font = FontCollection::new(
+        &fonts
+            .iter()
+            .map(|(font, scale)| (*font, TITLE_FONT_SIZE * scale))
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let left = if window_controls {
+        WINDOW_CONTROLS_WIDTH
+    } else {
+        CODE_PADDING
+    };
+    let available = image.width().saturating_sub(left + CODE_PADDING);
+    let mut text = title.to_owned();
+    let mut chars = title.chars().count();
+    while font.get_text_len(&text) > available {
+        if chars == 0 {
+            return;
+        }
+        chars -= 1;
+        text = title
+            .chars()
+            .take(chars)
+            .chain(std::iter::once('…'))
+            .collect();
+    }
+
+    let x = ((image.width() - font.get_text_len(&text)) / 2).max(left);
+    let y = TITLE_BAR_CENTER.saturating_sub(font.get_font_height() / 2);
+    font.draw_text_mut(image, color.to_rgba(), x, y, TextStyle::REGULAR, &text);
+}
+
+/// The colors of a diagonal gradient made from the theme's colors, for the canvas behind a fitted
+/// window.
+fn gradient_colors(background: Color, foreground: Color) -> (Color, Color) {
+    (
+        mix_colors(background, foreground, 0.25),
+        mix_colors(background, Color::BLACK, 0.35),
+    )
+}
+
+fn gradient(width: u32, height: u32, background: Color, foreground: Color) -> RgbaImage {
+    let (from, to) = gradient_colors(background, foreground);
+    RgbaImage::from_fn(width, height, |x, y| {
+        let amount = (x as f32 / width as f32 + y as f32 / height as f32) / 2.0;
+        let color = mix_colors(from, to, amount);
+        Rgba([color.r, color.g, color.b, 255])
+    })
+}
+
+/// Scales `image` down to fit on `canvas`, and centers it with a shadow.
+fn fit_to_canvas(image: &DynamicImage, canvas: RgbaImage, shadow_blur: f32) -> DynamicImage {
+    let (width, height) = canvas.dimensions();
+    let padding = width.min(height) / 12;
+    let (max_width, max_height) = (width - padding * 2, height - padding * 2);
+    let image = if image.width() > max_width || image.height() > max_height {
+        image.resize(max_width, max_height, FilterType::Lanczos3)
+    } else {
+        image.clone()
+    };
+
+    let shadowed = ShadowAdder::new()
+        .background(Background::Image(canvas.clone()))
+        .blur_radius(shadow_blur)
+        .pad_horiz((width - image.width()) / 2)
+        .pad_vert((height - image.height()) / 2)
+        .apply_to(&image);
+
+    // The padding is rounded down, so the shadowed image can be a pixel short of the canvas.
+    let mut canvas = DynamicImage::ImageRgba8(canvas);
+    imageops::overlay(&mut canvas, &shadowed, 0, 0);
+    canvas
+}
+
+/// Puts a window on a background with a shadow, like silicon does, or on a canvas when fitting it.
+fn svg_document(
+    window: &SvgWindow,
+    options: &TextImageOptions,
+    background: Color,
+    foreground: Color,
+) -> String {
+    let canvas_color = options
+        .background
+        .map(|Rgba([r, g, b, a])| Color { r, g, b, a });
+    let (width, height, fit_scale) = match options.fit.canvas_size(1) {
+        Some((width, height)) => {
+            let padding = width.min(height) as f32 / 12.0;
+            let fit_scale = ((width as f32 - padding * 2.0) / window.width)
+                .min((height as f32 - padding * 2.0) / window.height)
+                .min(1.0);
+            (width as f32, height as f32, fit_scale)
+        }
+        None => (
+            window.width + options.pad_x as f32 * 2.0,
+            window.height + options.pad_y as f32 * 2.0,
+            1.0,
+        ),
+    };
+    let (x, y) = (
+        (width - window.width * fit_scale) / 2.0,
+        (height - window.height * fit_scale) / 2.0,
+    );
+    let (from, to) = gradient_colors(background, foreground);
+    let gradient = options.fit != Fit::Natural && canvas_color.is_none();
+    let canvas_color = canvas_color.unwrap_or(SHADOW_BACKGROUND);
+    let number = renderer::svg_number;
+    let scale = options.scale as f32;
+
+    html! {
+        svg xmlns="http://www.w3.org/2000/svg" width=(number(width * scale))
+            height=(number(height * scale)) viewBox={ "0 0 " (number(width)) " " (number(height)) } {
+            defs {
+                @if gradient {
+                    linearGradient id="canvas" x1="0" y1="0" x2="1" y2="1" {
+                        stop offset="0" stop-color=(renderer::svg_color(from)) {}
+                        stop offset="1" stop-color=(renderer::svg_color(to)) {}
+                    }
+                }
+                @if options.shadow_blur > 0.0 {
+                    filter id="shadow" x="-50%" y="-50%" width="200%" height="200%" {
+                        feGaussianBlur stdDeviation=(number(options.shadow_blur / 2.0)) {}
+                    }
+                }
+            }
+            @if gradient {
+                rect width="100%" height="100%" fill="url(#canvas)" {}
+            } @else {
+                rect width="100%" height="100%" fill=(renderer::svg_color(canvas_color))
+                    fill-opacity=[renderer::svg_opacity(canvas_color)] {}
+            }
+            g transform={ "translate(" (number(x)) " " (number(y)) ") scale(" (number(fit_scale)) ")" } {
+                @if options.shadow_blur > 0.0 {
+                    rect width=(number(window.width)) height=(number(window.height)) rx="12"
+                        fill=(renderer::svg_color(SHADOW_COLOR)) filter="url(#shadow)" {}
+                }
+                (window.markup)
+            }
+        }
+    }
+    .into_string()
+}
+
+/// Lowers `scale` until an image that's `size` big at a scale of 1 fits in `max_pixels`.
+fn fit_pixel_budget(
+    (width, height): (f32, f32),
+    scale: u32,
+    max_pixels: u64,
+) -> Result<u32, QueryError> {
+    let max_scale = (max_pixels as f32 / (width * height)).sqrt() as u32;
+    if max_scale == 0 {
+        return Err(QueryError::new(format!(
+            "The image would be bigger than the limit of {} pixels",
+            max_pixels
+        )));
+    }
+    Ok(scale.min(max_scale))
+}
+
+/// Adds the faces of `families`, a comma-separated list, from `system` to `db`.
+fn add_families(db: &mut fontdb::Database, system: &fontdb::Database, families: &str) {
+    let families = families.split(',').map(str::trim).collect::<Vec<_>>();
+    for face in system.faces() {
+        let wanted = face.families.iter().any(|(name, _)| {
+            families
+                .iter()
+                .any(|family| family.eq_ignore_ascii_case(name))
+        });
+        if wanted {
+            db.push_face_info(face.clone());
+        }
+    }
+}
+
+/// The name that `family` is loaded under, ignoring case.
+fn loaded_family<'a>(db: &'a fontdb::Database, family: &str) -> Option<&'a str> {
+    db.faces()
+        .flat_map(|face| &face.families)
+        .map(|(name, _)| name.as_str())
+        .find(|name| name.eq_ignore_ascii_case(family))
+}
+
+/// Swaps the font families of SVG text for the names they're loaded under, since fontdb matches
+/// them case-sensitively while browsers don't.
+fn match_font_families(tree: &usvg::Tree, db: &fontdb::Database) {
+    for node in tree.root.descendants() {
+        let usvg::NodeKind::Text(ref mut text) = *node.borrow_mut() else {
+            continue;
+        };
+        for span in text.chunks.iter_mut().flat_map(|chunk| &mut chunk.spans) {
+            for family in &mut span.font.families {
+                if let Some(name) = loaded_family(db, family) {
+                    *family = name.to_owned();
+                }
+            }
+        }
+    }
+}
+
+/// Parses `?bg=`, with or without the leading `#`.
+fn parse_bg_color(bg: &str) -> Result<Rgba<u8>, QueryError> {
+    let bg = if bg.starts_with('#') {
+        bg.to_owned()
+    } else {
+        format!("#{}", bg)
+    };
+    bg.to_rgba()
+        .map_err(|_| QueryError::new("`bg` must be a hex color such as #abb8c3"))
+}
+
+/// Fills a pixmap with the grey and white squares that image editors show under transparency.
+fn fill_checkerboard(pixmap: &mut tiny_skia::Pixmap, scale: f32) {
+    let square = (CHECKERBOARD_SQUARE as f32 * scale).round() as u32;
+    let Some(mut tile) = tiny_skia::Pixmap::new(square * 2, square * 2) else {
+        return;
+    };
+    tile.fill(tiny_skia::Color::WHITE);
+    let mut paint = tiny_skia::Paint::default();
+    paint.set_color_rgba8(CHECKERBOARD_GREY, CHECKERBOARD_GREY, CHECKERBOARD_GREY, 255);
+    for offset in [0, square] {
+        if let Some(rect) =
+            tiny_skia::Rect::from_xywh(offset as f32, offset as f32, square as f32, square as f32)
+        {
+            tile.fill_rect(rect, &paint, tiny_skia::Transform::identity(), None);
+        }
+    }
+    paint.shader = tiny_skia::Pattern::new(
+        tile.as_ref(),
+        tiny_skia::SpreadMode::Repeat,
+        tiny_skia::FilterQuality::Nearest,
+        1.0,
+        tiny_skia::Transform::identity(),
+    );
+    if let Some(rect) =
+        tiny_skia::Rect::from_xywh(0.0, 0.0, pixmap.width() as f32, pixmap.height() as f32)
+    {
+        pixmap.fill_rect(rect, &paint, tiny_skia::Transform::identity(), None);
+    }
+}
+
+/// How an SVG is scaled when both `?width=` and `?height=` are given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SvgFit {
+    /// Scaled to fit inside the image, with the background around it.
+    #[default]
+    Contain,
+    /// Scaled to cover the whole image, cutting off whatever sticks out.
+    Cover,
+    /// Stretched to the size of the image.
+    Fill,
+}
+
+/// What's drawn under the transparent parts of an SVG.
+enum SvgBackground {
+    Color(tiny_skia::Color),
+    Checkerboard,
+}
+
+/// Where an SVG goes in the image it's rasterized to, at a scale of 1.
+struct SvgLayout {
+    size: (f32, f32),
+    padding: f32,
+    /// The area inside the padding, which the SVG is cut off at.
+    inner: (f32, f32),
+    transform: tiny_skia::Transform,
+}
+
+impl SvgLayout {
+    fn new(
+        svg: (f32, f32),
+        width: Option<u32>,
+        height: Option<u32>,
+        padding: u32,
+        fit: SvgFit,
+    ) -> Option<Self> {
+        let inner = |size: u32| size.checked_sub(padding * 2).filter(|size| *size > 0);
+        let ((inner_width, inner_height), (scale_x, scale_y)) = match (width, height) {
+            (Some(width), Some(height)) => {
+                let (width, height) = (inner(width)? as f32, inner(height)? as f32);
+                let (scale_x, scale_y) = (width / svg.0, height / svg.1);
+                let scale = match fit {
+                    SvgFit::Contain => (scale_x.min(scale_y), scale_x.min(scale_y)),
+                    SvgFit::Cover => (scale_x.max(scale_y), scale_x.max(scale_y)),
+                    SvgFit::Fill => (scale_x, scale_y),
+                };
+                ((width, height), scale)
+            }
+            (Some(width), None) => {
+                let scale = inner(width)? as f32 / svg.0;
+                ((svg.0 * scale, svg.1 * scale), (scale, scale))
+            }
+            (None, Some(height)) => {
+                let scale = inner(height)? as f32 / svg.1;
+                ((svg.0 * scale, svg.1 * scale), (scale, scale))
+            }
+            (None, None) => return None,
+        };
+        let padding = padding as f32;
+        Some(Self {
+            size: (inner_width + padding * 2.0, inner_height + padding * 2.0),
+            padding,
+            inner: (inner_width, inner_height),
+            transform: tiny_skia::Transform::from_row(
+                scale_x,
+                0.0,
+                0.0,
+                scale_y,
+                (inner_width - svg.0 * scale_x) / 2.0,
+                (inner_height - svg.1 * scale_y) / 2.0,
+            ),
+        })
+    }
+}
+
+/// What an SVG refers to that isn't loaded, from [`SvgImageGenerator::missing_resources`].
+pub(crate) struct MissingSvgResources {
+    /// The `href`s of the images besides data URLs.
+    pub(crate) image_hrefs: Vec<String>,
+    /// The families of text that has none of its families loaded.
+    pub(crate) font_families: Vec<String>,
+}
+
+/// What was downloaded for an SVG.
+#[derive(Default)]
+pub(crate) struct SvgResources {
+    /// Images keyed by their `href`.
+    pub(crate) images: HashMap<String, Arc<Vec<u8>>>,
+    /// Font files for the families that aren't loaded.
+    pub(crate) fonts: Vec<Vec<u8>>,
+}
+
+pub(crate) struct SvgImageGenerator {
+    db: fontdb::Database,
+    max_width: u32,
+    max_pixels: u64,
+    /// The listings of the directories that SVGs look for their font files in.
+    pub(crate) directories: DirectoryCache,
 }
 
 impl SvgImageGenerator {
-    pub(crate) fn generate(&self, buffer: &[u8]) -> Option<Vec<u8>> {
+    /// The width and height from `?width=`, `?height=` or `?fit=`, at a scale of 1. Without any of
+    /// them, SVGs are [`SVG_WIDTH`] wide.
+    fn requested_size(&self, query: &ImgQuery) -> Result<(Option<u32>, Option<u32>), QueryError> {
+        let width = check_query_range("width", query.width, 1, self.max_width)?;
+        let height = check_query_range("height", query.height, 1, self.max_width)?;
+        Ok(
+            match (width, height, query.fit.and_then(|fit| fit.canvas_size(1))) {
+                (None, None, Some((width, height))) => (Some(width), Some(height)),
+                (None, None, None) => (Some(SVG_WIDTH), None),
+                (width, height, _) => (width, height),
+            },
+        )
+    }
+
+    /// The size of the image that an SVG is rasterized to, when it doesn't depend on the SVG, for
+    /// `og:image:width` and `og:image:height`.
+    pub(crate) fn image_size(&self, query: &ImgQuery) -> Result<Option<(u32, u32)>, QueryError> {
+        let scale = check_query_range("scale", query.scale, 1, MAX_SCALE)?.unwrap_or(1);
+        let (Some(width), Some(height)) = self.requested_size(query)? else {
+            return Ok(None);
+        };
+        let scale = fit_pixel_budget((width as f32, height as f32), scale, self.max_pixels)?;
+        match (width.checked_mul(scale), height.checked_mul(scale)) {
+            (Some(width), Some(height)) => Ok(Some((width, height))),
+            _ => Err(QueryError::new("The image would be too big")),
+        }
+    }
+
+    /// Text in a font that lacks a character falls back to any loaded font that has it, such as the
+    /// system's CJK and emoji fonts in `fallback_fonts`, a comma-separated list of families that
+    /// are loaded even when `system_fonts` isn't set.
+    pub(crate) fn new(
+        max_width: u32,
+        max_pixels: u64,
+        fonts_dir: Option<&str>,
+        system_fonts: bool,
+        fallback_fonts: &str,
+    ) -> Self {
+        let mut system = fontdb::Database::new();
+        system.load_system_fonts();
+        let mut db = if system_fonts {
+            system
+        } else {
+            let mut db = fontdb::Database::new();
+            add_families(&mut db, &system, fallback_fonts);
+            db
+        };
+        for font in fonts::BUNDLED_FONTS {
+            db.load_font_data(font.to_vec());
+        }
+        if let Some(dir) = fonts_dir {
+            db.load_fonts_dir(dir);
+        }
+        Self::with_fonts(max_width, max_pixels, db)
+    }
+
+    fn with_fonts(max_width: u32, max_pixels: u64, mut db: fontdb::Database) -> Self {
+        let cursive = SVG_CURSIVE_FAMILIES
+            .iter()
+            .find(|family| loaded_family(&db, family).is_some())
+            .copied()
+            .unwrap_or(SVG_SANS_SERIF);
+        db.set_sans_serif_family(SVG_SANS_SERIF);
+        db.set_fantasy_family(SVG_SANS_SERIF);
+        db.set_monospace_family(fonts::DEFAULT_FONT);
+        // usvg also falls back to the serif family for text whose families aren't loaded.
+        db.set_serif_family(SVG_SERIF);
+        db.set_cursive_family(cursive);
+        Self {
+            db,
+            max_width,
+            max_pixels,
+            directories: DirectoryCache::default(),
+        }
+    }
+
+    /// What an SVG refers to that has to be downloaded before it's drawn. Returns `None` for files
+    /// that aren't SVG.
+    pub(crate) fn missing_resources(&self, buffer: &[u8]) -> Option<MissingSvgResources> {
+        let hrefs = Arc::new(Mutex::new(Vec::new()));
+        let options = usvg::Options {
+            font_family: SVG_SANS_SERIF.to_string(),
+            image_href_resolver: ImageHrefResolver {
+                resolve_data: Box::new(|_, _, _| None),
+                resolve_string: Box::new({
+                    let hrefs = hrefs.clone();
+                    move |href, _| {
+                        let mut hrefs = hrefs.lock().unwrap();
+                        if !hrefs.iter().any(|known| known == href) {
+                            hrefs.push(href.to_owned());
+                        }
+                        None
+                    }
+                }),
+            },
+            ..Default::default()
+        };
+        let tree = usvg::Tree::from_data(buffer, &options).ok()?;
+
+        let mut font_families = Vec::<String>::new();
+        for node in tree.root.descendants() {
+            let usvg::NodeKind::Text(ref text) = *node.borrow() else {
+                continue;
+            };
+            for span in text.chunks.iter().flat_map(|chunk| &chunk.spans) {
+                // Text falls back to the next family in its list, so only the families of text
+                // that has none of its families loaded are missing.
+                let families = &span.font.families;
+                if families.iter().any(|family| self.has_family(family)) {
+                    continue;
+                }
+                for family in families {
+                    if !font_families.contains(family) {
+                        font_families.push(family.clone());
+                    }
+                }
+            }
+        }
+
+        let image_hrefs = hrefs.lock().unwrap().clone();
+        Some(MissingSvgResources {
+            image_hrefs,
+            font_families,
+        })
+    }
+
+    fn has_family(&self, family: &str) -> bool {
+        matches!(
+            family,
+            "serif" | "sans-serif" | "cursive" | "fantasy" | "monospace"
+        ) || loaded_family(&self.db, family).is_some()
+    }
+
+    /// Returns `None` for files that aren't SVG. Images that aren't data URLs or in `resources`
+    /// are left out.
+    pub(crate) fn generate_from_query(
+        &self,
+        buffer: &[u8],
+        resources: SvgResources,
+        query: &ImgQuery,
+    ) -> Result<Option<DynamicImage>, QueryError> {
+        let SvgResources { images, fonts } = resources;
+        let resolve_data = ImageHrefResolver::default_data_resolver();
         let options = usvg::Options {
-            font_family: "sans-serif".to_string(),
+            font_family: SVG_SANS_SERIF.to_string(),
             dpi: 96.0,
+            image_href_resolver: ImageHrefResolver {
+                resolve_data: ImageHrefResolver::default_data_resolver(),
+                // usvg reads other images from files by default, which would be the server's.
+                resolve_string: Box::new(move |href, options| {
+                    // A MIME type of text/plain has usvg look at the data to tell what it is.
+                    resolve_data("text/plain", images.get(href)?.clone(), options)
+                }),
+            },
             ..Default::default()
         };
-        let mut tree = usvg::Tree::from_data(buffer, &options).ok()?;
+        let Ok(mut tree) = usvg::Tree::from_data(buffer, &options) else {
+            return Ok(None);
+        };
         if tree.has_text_nodes() {
-            tree.convert_text(&self.db);
+            let db = if fonts.is_empty() {
+                Cow::Borrowed(&self.db)
+            } else {
+                let mut db = self.db.clone();
+                for font in fonts {
+                    db.load_font_data(font);
+                }
+                Cow::Owned(db)
+            };
+            match_font_families(&tree, &db);
+            tree.convert_text(&db);
         }
         let rtree = resvg::Tree::from_usvg(&tree);
-        let size = rtree.size.to_int_size().scale_to_width(960)?;
-        let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())?;
-        let render_ts = tiny_skia::Transform::from_scale(
-            size.width() as f32 / tree.size.width(),
-            size.height() as f32 / tree.size.height(),
+
+        let scale = check_query_range("scale", query.scale, 1, MAX_SCALE)?.unwrap_or(1);
+        let (width, height) = self.requested_size(query)?;
+        let padding = check_query_range("padding", query.padding, 0, MAX_PAD)?.unwrap_or(0);
+        let background = match query.bg.as_deref() {
+            Some("checkerboard") => Some(SvgBackground::Checkerboard),
+            Some(bg) => {
+                let Rgba([r, g, b, a]) = parse_bg_color(bg)?;
+                Some(SvgBackground::Color(tiny_skia::Color::from_rgba8(
+                    r, g, b, a,
+                )))
+            }
+            None => None,
+        };
+        let Some(layout) = SvgLayout::new(
+            (tree.size.width(), tree.size.height()),
+            width,
+            height,
+            padding,
+            query.svg_fit.unwrap_or_default(),
+        ) else {
+            return Err(QueryError::new("`padding` must leave room for the image"));
+        };
+        let scale = fit_pixel_budget(layout.size, scale, self.max_pixels)? as f32;
+
+        let pixels = |size: f32| ((size * scale).round() as u32).max(1);
+        let Some(mut inner) =
+            tiny_skia::Pixmap::new(pixels(layout.inner.0), pixels(layout.inner.1))
+        else {
+            return Ok(None);
+        };
+        rtree.render(
+            layout.transform.post_scale(scale, scale),
+            &mut inner.as_mut(),
+        );
+        if background.is_none() && padding == 0 {
+            return Ok(Some(renderer::pixmap_to_image(&inner)));
+        }
+
+        let Some(mut pixmap) = tiny_skia::Pixmap::new(pixels(layout.size.0), pixels(layout.size.1))
+        else {
+            return Ok(None);
+        };
+        match background {
+            Some(SvgBackground::Color(color)) => pixmap.fill(color),
+            Some(SvgBackground::Checkerboard) => fill_checkerboard(&mut pixmap, scale),
+            None => {}
+        }
+        let offset = (layout.padding * scale).round() as i32;
+        pixmap.draw_pixmap(
+            offset,
+            offset,
+            inner.as_ref(),
+            &tiny_skia::PixmapPaint::default(),
+            tiny_skia::Transform::identity(),
+            None,
+        );
+        Ok(Some(renderer::pixmap_to_image(&pixmap)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Query;
+
+    use super::*;
+
+    fn query(query: &str) -> ImgQuery {
+        Query::<ImgQuery>::from_query(query).unwrap().into_inner()
+    }
+
+    #[test]
+    fn canvas_sizes_do_not_overflow() {
+        assert_eq!(Fit::Og.canvas_size(2), Some((2400, 1260)));
+        assert_eq!(Fit::Natural.canvas_size(1), None);
+        assert_eq!(Fit::Og.canvas_size(4_000_000), None);
+    }
+
+    #[test]
+    fn code_image_sizes_are_only_known_when_the_scale_is() {
+        let size = |q: &str, format| code_image_size(&query(q), format).ok();
+        assert_eq!(size("fit=og", Format::Png), Some(Some((1200, 630))));
+        assert_eq!(size("fit=twitter", Format::Svg), Some(Some((1200, 600))));
+        assert_eq!(
+            size("fit=og&scale=2", Format::Svg),
+            Some(Some((2400, 1260)))
         );
-        rtree.render(render_ts, &mut pixmap.as_mut());
-        pixmap.encode_png().ok()
+        assert_eq!(size("fit=og&scale=2", Format::Png), Some(None));
+        assert_eq!(size("scale=2", Format::Png), Some(None));
+    }
+
+    #[test]
+    fn rejects_scales_the_image_route_rejects() {
+        for q in [
+            "fit=og&scale=4000000",
+            "fit=og&scale=0",
+            "fit=og&scale=2&renderer=silicon",
+        ] {
+            assert!(code_image_size(&query(q), Format::Png).is_err(), "{}", q);
+        }
+    }
+
+    /// A TrueType font named `family` that only has `c`, drawn as a filled square.
+    fn square_font(family: &str, c: char) -> Vec<u8> {
+        fn be(words: &[i32]) -> Vec<u8> {
+            words
+                .iter()
+                .flat_map(|w| (*w as u16).to_be_bytes())
+                .collect()
+        }
+        let c = c as i32;
+        let delta = 1 - c;
+        // The family name doubles as the PostScript name, which fontdb requires.
+        let name = family.encode_utf16().flat_map(u16::to_be_bytes);
+        let len = family.len() as i32 * 2;
+        let tables: [(&[u8; 4], Vec<u8>); 8] = [
+            (
+                b"cmap",
+                be(&[
+                    0, 1, 3, 1, 0, 12, 4, 32, 0, 4, 4, 1, 0, c, 0xFFFF, 0, c, 0xFFFF, delta, 1, 0,
+                    0,
+                ]),
+            ),
+            (
+                b"glyf",
+                be(&[
+                    1, 0, 0, 800, 800, 3, 0, 0x0101, 0x0101, 0, 0, 800, 0, 0, 800, 0, -800,
+                ]),
+            ),
+            (
+                b"head",
+                be(&[
+                    1, 0, 1, 0, 0, 0, 0x5F0F, 0x3CF5, 0, 1000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 800,
+                    800, 0, 8, 0, 0, 0,
+                ]),
+            ),
+            (
+                b"hhea",
+                be(&[
+                    1, 0, 800, -200, 0, 1000, 0, 0, 800, 1, 0, 0, 0, 0, 0, 0, 0, 2,
+                ]),
+            ),
+            (b"hmtx", be(&[1000, 0, 1000, 0])),
+            (b"loca", be(&[0, 0, 17])),
+            (b"maxp", be(&[0, 0x5000, 2])),
+            (
+                b"name",
+                be(&[0, 2, 30, 3, 1, 0x409, 1, len, 0, 3, 1, 0x409, 6, len, 0])
+                    .into_iter()
+                    .chain(name)
+                    .collect(),
+            ),
+        ];
+
+        let mut font = be(&[1, 0, tables.len() as i32, 128, 3, 0]);
+        let mut offset = font.len() + tables.len() * 16;
+        let mut data = Vec::new();
+        for (tag, mut table) in tables {
+            font.extend(tag);
+            font.extend([0; 4]);
+            font.extend((offset as u32).to_be_bytes());
+            font.extend((table.len() as u32).to_be_bytes());
+            table.resize(table.len().next_multiple_of(4), 0);
+            offset += table.len();
+            data.extend(table);
+        }
+        font.extend(data);
+        font
+    }
+
+    #[test]
+    fn svg_text_falls_back_to_the_fallback_fonts() {
+        let mut system = fontdb::Database::new();
+        system.load_font_data(square_font("Test CJK", '中'));
+        system.load_font_data(square_font("Unused", '中'));
+        let render = |fallback_fonts| {
+            let mut db = fontdb::Database::new();
+            add_families(&mut db, &system, fallback_fonts);
+            for font in fonts::BUNDLED_FONTS {
+                db.load_font_data(font.to_vec());
+            }
+            let svg_img_gen = SvgImageGenerator::with_fonts(4096, 25_000_000, db);
+            let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="100">
+                <text x="10" y="90" font-family="monospace" font-size="80">&#x4E2D;</text>
+            </svg>"#;
+            svg_img_gen
+                .generate_from_query(svg, SvgResources::default(), &query("width=100&height=100"))
+                .unwrap()
+                .unwrap()
+                .to_rgba8()
+        };
+
+        let image = render("Noto Color Emoji, test cjk");
+        // Inside the square, where Hack's .notdef box is empty.
+        assert_eq!(image.get_pixel(40, 60).0[3], 255);
+        assert_eq!(render("Noto Color Emoji").get_pixel(40, 60).0[3], 0);
+    }
+
+    #[test]
+    fn separates_disjoint_line_ranges() {
+        let text_img_gen = TextImageGenerator::new(4096, 25_000_000, None, "").unwrap();
+        let lines = query("lines=2-3,10,12").lines.unwrap();
+        let lines = crate::utils::clamp_query_lines(&lines, 25);
+        let CodeImage::Svg(svg) = text_img_gen
+            .generate_from_query(
+                "two\nthree\nten\ntwelve\n",
+                &lines,
+                None,
+                None,
+                &query("format=svg"),
+            )
+            .unwrap()
+        else {
+            panic!("not an SVG");
+        };
+        let texts = svg
+            .split("</text>")
+            .filter_map(|part| Some(part.rsplit_once('>')?.1))
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            [" 2 ", "two", " 3 ", "three", " ⋯ ", "10 ", "ten", " ⋯ ", "12 ", "twelve"]
+        );
+    }
+
+    #[test]
+    fn rejects_wrapping_and_truncating_together() {
+        assert!(matches!(
+            resolve_overflow(&query("wrap=40")),
+            Ok(Some(Overflow::Wrap(40)))
+        ));
+        assert!(matches!(
+            resolve_overflow(&query("truncate=40")),
+            Ok(Some(Overflow::Truncate(40)))
+        ));
+        assert!(matches!(resolve_overflow(&query("")), Ok(None)));
+        assert!(resolve_overflow(&query("wrap=40&truncate=40")).is_err());
+    }
+
+    #[test]
+    fn negotiates_formats() {
+        for (accept, format) in [
+            ("", Format::Png),
+            ("*/*", Format::Png),
+            ("image/*", Format::Png),
+            ("text/html", Format::Png),
+            ("image/jpeg", Format::Jpeg),
+            ("IMAGE/JPEG ; q=0.5", Format::Jpeg),
+            // WebP is picked over PNG at the same quality.
+            ("image/png,image/webp", Format::Webp),
+            (
+                "image/avif,image/webp,image/apng,image/*,*/*;q=0.8",
+                Format::Webp,
+            ),
+            // Otherwise the highest quality wins.
+            ("image/webp;q=0.5,image/png", Format::Png),
+            ("image/png;q=0.5,image/jpeg;q=0.9", Format::Jpeg),
+            ("image/webp;q=0,image/jpeg;q=0.1", Format::Jpeg),
+            ("image/webp;q=0", Format::Png),
+            ("image/webp;q=x", Format::Png),
+            // The best of repeated ranges counts.
+            ("image/jpeg;q=0.1,image/png;q=0.5,image/jpeg", Format::Jpeg),
+        ] {
+            assert_eq!(Format::negotiate(accept), format, "{:?}", accept);
+        }
+    }
+
+    #[test]
+    fn serves_other_images_as_png_instead_of_svg() {
+        assert_eq!(Format::Svg.raster(), Format::Png);
+        for format in Format::NEGOTIABLE {
+            assert_eq!(format.raster(), format);
+        }
+    }
+
+    #[test]
+    fn flattens_images_on_the_matte() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 1, |x, _| {
+            [
+                Rgba([255, 0, 0, 255]),
+                Rgba([255, 0, 0, 0]),
+                Rgba([255, 0, 0, 128]),
+                Rgba([10, 20, 30, 51]),
+            ][x as usize]
+        }));
+        let flat = flatten(&image, Rgba([0, 0, 255, 255]));
+        assert_eq!(
+            flat.pixels().copied().collect::<Vec<_>>(),
+            [
+                Rgb([255, 0, 0]),
+                Rgb([0, 0, 255]),
+                Rgb([128, 0, 127]),
+                Rgb([2, 4, 210]),
+            ]
+        );
+        let opaque = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([1, 2, 3])));
+        assert_eq!(flatten(&opaque, WHITE), opaque.to_rgb8());
+    }
+
+    #[test]
+    fn expands_tabs() {
+        for (code, expanded) in [
+            ("\tx", "    x"),
+            ("ab\tx\tyz\t!", "ab  x   yz  !"),
+            // Mixed tabs and spaces.
+            ("  \tx", "    x"),
+            (" \t \tx", "        x"),
+            ("\t  x", "      x"),
+            // Wide characters take up two columns.
+            ("国\tx", "国  x"),
+            ("a国\tx", "a国 x"),
+            ("国国\tx", "国国    x"),
+            // Every line starts at column 0, whatever its line ending.
+            ("abc\n\tx", "abc\n    x"),
+            ("abc\r\n\tx\r\n", "abc\r\n    x\r\n"),
+        ] {
+            assert_eq!(expand_tabs(code, 4), expanded, "{:?}", code);
+        }
+        assert_eq!(expand_tabs("a\tb", 1), "a b");
+    }
+
+    #[test]
+    fn dedents_code() {
+        for (code, dedented) in [
+            ("    a\n      b\n    c\n", "a\n  b\nc\n"),
+            ("a\n  b\n", "a\n  b\n"),
+            // Blank lines don't count toward the indent, and keep what's past it.
+            ("    a\n\n  \n        \n    b", "a\n\n\n    \nb"),
+            ("    a\r\n\r\n      b\r\n", "a\r\n\r\n  b\r\n"),
+            ("  \r\n    a\r\n", "\r\na\r\n"),
+            // Tabs are expanded first, so only spaces are indentation.
+            ("\ta\n  b", "\ta\n  b"),
+            ("", ""),
+            ("   ", "   "),
+        ] {
+            assert_eq!(dedent(code), dedented, "{:?}", code);
+        }
+        assert_eq!(dedent(&expand_tabs("\ta\n  b", 4)), "  a\nb");
+    }
+
+    #[test]
+    fn splits_tokens_into_rows() {
+        let style = Style::default();
+        let split = |tokens: &[&'static str], columns| {
+            let tokens = tokens.iter().map(|text| (style, *text)).collect::<Vec<_>>();
+            split_tokens(&tokens, columns)
+                .into_iter()
+                .map(|row| row.into_iter().map(|(_, text)| text).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(split(&["let x", " = 1;\n"], 10), [vec!["let x", " = 1;"]]);
+        assert_eq!(
+            split(&["let x", " = 1;"], 5),
+            [vec!["let x"], vec![" = 1;"]]
+        );
+        assert_eq!(
+            split(&["let", " x = 1;"], 4),
+            [vec!["let", " "], vec!["x = "], vec!["1;"]]
+        );
+        // Wide characters take up two columns and aren't split across rows.
+        assert_eq!(split(&["a国国"], 4), [vec!["a国"], vec!["国"]]);
+        assert_eq!(split(&["国国"], 4), [vec!["国国"]]);
+        // A character wider than a row gets a row of its own.
+        assert_eq!(split(&["a国b"], 1), [vec!["a"], vec!["国"], vec!["b"]]);
+        assert_eq!(split(&[""], 4), [Vec::<&str>::new()]);
+    }
+
+    #[actix_web::test]
+    async fn png_optimization_falls_back_to_the_unoptimized_png() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(1024, 1024, |x, y| {
+            Rgb([(x * y) as u8, (x ^ y) as u8, (x + y) as u8])
+        }));
+        let png_optimization = PngOptimization {
+            level: PngOptimization::MAX_LEVEL,
+            timeout: Duration::ZERO,
+        };
+        let png = encode_image(image, Format::Png, WHITE, Some(&png_optimization)).await;
+        let png = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap();
+        assert_eq!((png.width(), png.height()), (1024, 1024));
+    }
+
+    #[test]
+    fn svg_image_sizes_stay_within_the_limits() {
+        let svg_img_gen = SvgImageGenerator::new(4096, 25_000_000, None, false, "");
+        let size = |q: &str| svg_img_gen.image_size(&query(q)).ok();
+        assert_eq!(
+            size("width=800&height=600&scale=2"),
+            Some(Some((1600, 1200)))
+        );
+        assert_eq!(size("fit=og&scale=3"), Some(Some((3600, 1890))));
+        // Lowered to a scale of 1 by the pixel budget.
+        assert_eq!(
+            size("width=4000&height=4000&scale=3"),
+            Some(Some((4000, 4000)))
+        );
+        assert_eq!(size("width=800"), Some(None));
+        assert_eq!(size(""), Some(None));
+        for q in [
+            "width=4294967295&height=4294967295&scale=3",
+            "width=800&height=600&scale=4000000",
+            "width=4097&height=10",
+            "width=800&height=0",
+        ] {
+            assert!(size(q).is_none(), "{}", q);
+        }
     }
 }
@@ -1,17 +1,27 @@
 #[macro_use]
 extern crate lazy_static;
 
+use std::time::Duration;
+
 use actix_web::{web, App, HttpServer};
 
 use regex::Regex;
 
 use dotenv::dotenv;
 
+mod classify;
+#[allow(clippy::double_ended_iterator_last)]
 mod content;
 mod errors;
+mod fonts;
+mod gitattributes;
 mod image_generator;
+mod raster;
+mod renderer;
 mod routes;
+mod syntax;
 mod utils;
+mod video;
 
 lazy_static! {
     static ref UA_REGEX: Regex = Regex::new(r"bot|facebook|embed|got|firefox/92|firefox/38|curl|wget|go-http|yahoo|generator|whatsapp|preview|link|proxy|vkshare|images|analyzer|index|crawl|spider|python|cfnetwork|node").unwrap();
@@ -23,6 +33,28 @@ struct Options {
     origin: String,
     max_download_bytes: u32,
     max_code_lines: u32,
+    max_image_width: u32,
+    /// The most pixels in an image, which lowers `?scale=` for images that would be bigger. Raster
+    /// files with more pixels aren't decoded.
+    max_image_pixels: u64,
+    /// The longest side of the raster files that are re-encoded for crawlers.
+    raster_max_dimension: u32,
+    /// PNG, JPEG and GIF files up to this size are shown to crawlers as they are, and the others
+    /// are re-encoded.
+    max_og_image_bytes: u32,
+    /// A directory of extra `.tmTheme` and `.sublime-syntax` files.
+    assets_dir: Option<String>,
+    /// A comma-separated list of font families for characters that code fonts lack.
+    fallback_fonts: String,
+    /// `None` writes PNGs as they come out of the encoder.
+    png_optimization: Option<image_generator::PngOptimization>,
+    /// A directory of extra fonts for the text in SVG files.
+    svg_fonts_dir: Option<String>,
+    /// Whether the text in SVG files may be drawn with the system's fonts, which makes the images
+    /// depend on what's installed on the host.
+    svg_system_fonts: bool,
+    /// The hosts that SVG files may load images from, besides their own repository.
+    svg_image_hosts: Vec<String>,
 }
 impl Default for Options {
     fn default() -> Self {
@@ -31,6 +63,23 @@ impl Default for Options {
             origin: "http://localhost:8080".to_string(),
             max_download_bytes: 1024 * 1024 * 50, // 25 MiB
             max_code_lines: 25,
+            max_image_width: 4096,
+            max_image_pixels: 25_000_000,
+            raster_max_dimension: 2048,
+            max_og_image_bytes: 1024 * 1024 * 5,
+            assets_dir: None,
+            fallback_fonts: fonts::DEFAULT_FALLBACK_FONTS.to_string(),
+            png_optimization: Some(image_generator::PngOptimization {
+                level: 1,
+                timeout: Duration::from_millis(1000),
+            }),
+            svg_fonts_dir: None,
+            svg_system_fonts: false,
+            svg_image_hosts: vec![
+                "user-images.githubusercontent.com".to_string(),
+                "avatars.githubusercontent.com".to_string(),
+                "camo.githubusercontent.com".to_string(),
+            ],
         }
     }
 }
@@ -55,22 +104,101 @@ async fn main() -> std::io::Result<()> {
             .ok()
             .and_then(|lines| lines.parse::<u32>().ok())
             .unwrap_or(default_options.max_code_lines),
+        max_image_width: std::env::var("MAX_IMAGE_WIDTH")
+            .ok()
+            .and_then(|width| width.parse::<u32>().ok())
+            .unwrap_or(default_options.max_image_width),
+        max_image_pixels: std::env::var("MAX_IMAGE_PIXELS")
+            .ok()
+            .and_then(|pixels| pixels.parse::<u64>().ok())
+            .unwrap_or(default_options.max_image_pixels),
+        raster_max_dimension: std::env::var("RASTER_MAX_DIMENSION")
+            .ok()
+            .and_then(|dimension| dimension.parse::<u32>().ok())
+            .unwrap_or(default_options.raster_max_dimension),
+        max_og_image_bytes: std::env::var("MAX_OG_IMAGE_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse::<u32>().ok())
+            .unwrap_or(default_options.max_og_image_bytes),
+        assets_dir: std::env::var("ASSETS_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .or(default_options.assets_dir),
+        fallback_fonts: std::env::var("FALLBACK_FONTS").unwrap_or(default_options.fallback_fonts),
+        png_optimization: match std::env::var("PNG_OPTIMIZATION_LEVEL").ok().as_deref() {
+            Some("off") => None,
+            level => {
+                let default = default_options.png_optimization.unwrap();
+                Some(image_generator::PngOptimization {
+                    level: level
+                        .and_then(|level| level.parse::<u8>().ok())
+                        .unwrap_or(default.level)
+                        .min(image_generator::PngOptimization::MAX_LEVEL),
+                    timeout: std::env::var("PNG_OPTIMIZATION_TIMEOUT_MS")
+                        .ok()
+                        .and_then(|timeout| timeout.parse::<u64>().ok())
+                        .map_or(default.timeout, Duration::from_millis),
+                })
+            }
+        },
+        svg_fonts_dir: std::env::var("SVG_FONTS_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .or(default_options.svg_fonts_dir),
+        svg_system_fonts: std::env::var("SVG_SYSTEM_FONTS")
+            .ok()
+            .and_then(|enabled| enabled.parse::<bool>().ok())
+            .unwrap_or(default_options.svg_system_fonts),
+        svg_image_hosts: std::env::var("SVG_IMAGE_HOSTS").map_or(
+            default_options.svg_image_hosts,
+            |hosts| {
+                hosts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            },
+        ),
     };
 
     let port = options.port;
 
+    // Loading the highlighting assets and fonts is slow, so share one generator of each kind
+    // between the workers.
+    let text_img_gen = web::Data::new(
+        image_generator::TextImageGenerator::new(
+            options.max_image_width,
+            options.max_image_pixels,
+            options.assets_dir.as_deref(),
+            &options.fallback_fonts,
+        )
+        .expect("Unable to load the themes and syntaxes in ASSETS_DIR"),
+    );
+    let svg_img_gen = web::Data::new(image_generator::SvgImageGenerator::new(
+        options.max_image_width,
+        options.max_image_pixels,
+        options.svg_fonts_dir.as_deref(),
+        options.svg_system_fonts,
+        &options.fallback_fonts,
+    ));
+    let gitattributes_cache = web::Data::new(gitattributes::GitAttributesCache::default());
+
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(options.clone()))
-            .app_data(web::Data::new(
-                image_generator::TextImageGenerator::default(),
-            ))
-            .app_data(web::Data::new(image_generator::SvgImageGenerator::default()))
+            .app_data(text_img_gen.clone())
+            .app_data(svg_img_gen.clone())
+            .app_data(gitattributes_cache.clone())
             .service(routes::get_gh_open_graph)
             .service(routes::get_gh_image)
+            .service(routes::get_gh_raster)
             .service(routes::get_gh_video_embed)
             .service(routes::get_gist_open_graph)
             .service(routes::get_gist_image)
+            .service(routes::get_themes)
+            .service(routes::get_fonts)
+            .service(routes::get_gallery)
             .service(routes::get_other_pages)
     })
     .bind(("0.0.0.0", port))?
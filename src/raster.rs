@@ -0,0 +1,279 @@
+use std::io::Cursor;
+
+use image::{
+    imageops::FilterType,
+    io::{Limits, Reader},
+    DynamicImage, ImageResult,
+};
+
+use crate::image_generator::Format;
+
+/// The types of images that crawlers show as they are, as long as they aren't too big.
+pub(crate) const CRAWLER_MIME_TYPES: &[&str] =
+    &["image/png", "image/jpeg", "image/jpg", "image/gif"];
+
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// The format that an image of `mime_type` is re-encoded as: JPEG for JPEGs, which are usually
+/// photos, and PNG for everything else, which may be transparent.
+pub(crate) fn default_format(mime_type: &str) -> Format {
+    if mime_type.contains("image/jpeg") || mime_type.contains("image/jpg") {
+        Format::Jpeg
+    } else {
+        Format::Png
+    }
+}
+
+/// Decodes an image, turns it upright according to its EXIF orientation, and shrinks it to fit in
+/// `max_dimension` by `max_dimension`. Images with more than `max_pixels` aren't decoded at all.
+pub(crate) fn prepare(
+    data: &[u8],
+    max_dimension: u32,
+    max_pixels: u64,
+) -> ImageResult<DynamicImage> {
+    let mut reader = Reader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    // Enough for 16-bit RGBA.
+    limits.max_alloc = Some(max_pixels.saturating_mul(8));
+    reader.limits(limits);
+
+    let image = orient(reader.decode()?, exif_orientation(data).unwrap_or(1));
+    Ok(
+        if image.width() > max_dimension || image.height() > max_dimension {
+            image.resize(max_dimension, max_dimension, FilterType::Lanczos3)
+        } else {
+            image
+        },
+    )
+}
+
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// The EXIF orientation of a JPEG, PNG, WebP or TIFF file, from 1 to 8.
+fn exif_orientation(data: &[u8]) -> Option<u16> {
+    let tiff = find_exif(data)?;
+    let big_endian = match tiff.get(..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| {
+        let bytes = tiff.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |offset: usize| {
+        let bytes = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    (0..u16_at(ifd)? as usize)
+        .map(|entry| ifd + 2 + entry * 12)
+        .find(|entry| u16_at(*entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+/// The TIFF structure that a file keeps its EXIF data in.
+fn find_exif(data: &[u8]) -> Option<&[u8]> {
+    if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        return Some(data);
+    }
+    if data.starts_with(&[0xFF, 0xD8]) {
+        let mut rest = &data[2..];
+        while rest.len() >= 4 && rest[0] == 0xFF {
+            let marker = rest[1];
+            // The start of the image data, which no metadata comes after.
+            if marker == 0xDA {
+                return None;
+            }
+            let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let segment = rest.get(4..2 + length)?;
+            if marker == 0xE1 {
+                if let Some(exif) = segment.strip_prefix(b"Exif\0\0") {
+                    return Some(exif);
+                }
+            }
+            rest = &rest[2 + length..];
+        }
+        return None;
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let mut rest = &data[8..];
+        while rest.len() >= 12 {
+            let length = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+            let chunk = rest.get(8..8 + length)?;
+            if &rest[4..8] == b"eXIf" {
+                return Some(chunk);
+            }
+            rest = rest.get(12 + length..)?;
+        }
+        return None;
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        let mut rest = &data[12..];
+        while rest.len() >= 8 {
+            let length = u32::from_le_bytes(rest[4..8].try_into().ok()?) as usize;
+            let chunk = rest.get(8..8 + length)?;
+            if &rest[..4] == b"EXIF" {
+                return Some(chunk.strip_prefix(b"Exif\0\0").unwrap_or(chunk));
+            }
+            // Chunks are padded to an even length.
+            rest = rest.get(8 + length + length % 2..)?;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageOutputFormat, RgbImage};
+
+    use super::*;
+
+    /// A TIFF structure whose first IFD holds an unrelated tag and the orientation.
+    fn tiff(big_endian: bool, orientation: u16) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let mut tiff = if big_endian {
+            b"MM".to_vec()
+        } else {
+            b"II".to_vec()
+        };
+        tiff.extend(u16_bytes(42));
+        tiff.extend(if big_endian {
+            8u32.to_be_bytes()
+        } else {
+            8u32.to_le_bytes()
+        });
+        tiff.extend(u16_bytes(2));
+        for (tag, value) in [(0x010F, 0), (ORIENTATION_TAG, orientation)] {
+            tiff.extend(u16_bytes(tag));
+            tiff.extend(u16_bytes(3));
+            tiff.extend(if big_endian {
+                1u32.to_be_bytes()
+            } else {
+                1u32.to_le_bytes()
+            });
+            tiff.extend(u16_bytes(value));
+            tiff.extend([0, 0]);
+        }
+        tiff
+    }
+
+    /// A JPEG's start followed by a JFIF segment and an EXIF segment.
+    fn jpeg_exif(tiff: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00];
+        jpeg.extend([0xFF, 0xE1]);
+        jpeg.extend((2 + 6 + tiff.len() as u16).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg
+    }
+
+    #[test]
+    fn reads_the_orientation_of_tiff_and_jpeg() {
+        assert_eq!(exif_orientation(&tiff(false, 6)), Some(6));
+        assert_eq!(exif_orientation(&tiff(true, 8)), Some(8));
+        assert_eq!(exif_orientation(&jpeg_exif(&tiff(false, 3))), Some(3));
+        assert_eq!(exif_orientation(&jpeg_exif(&tiff(true, 5))), Some(5));
+    }
+
+    #[test]
+    fn reads_the_orientation_of_png_and_webp() {
+        let exif = tiff(false, 6);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(13u32.to_be_bytes());
+        png.extend(b"IHDR");
+        png.extend([0; 13 + 4]);
+        png.extend((exif.len() as u32).to_be_bytes());
+        png.extend(b"eXIf");
+        png.extend(&exif);
+        png.extend([0; 4]);
+        assert_eq!(exif_orientation(&png), Some(6));
+
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend(b"VP8X");
+        webp.extend(1u32.to_le_bytes());
+        webp.extend([0, 0]);
+        webp.extend(b"EXIF");
+        webp.extend((exif.len() as u32 + 6).to_le_bytes());
+        webp.extend(b"Exif\0\0");
+        webp.extend(&exif);
+        assert_eq!(exif_orientation(&webp), Some(6));
+    }
+
+    #[test]
+    fn ignores_missing_and_malformed_exif() {
+        assert_eq!(exif_orientation(b""), None);
+        assert_eq!(exif_orientation(b"GIF89a"), None);
+        // A JPEG whose image data starts before any EXIF.
+        assert_eq!(exif_orientation(&[0xFF, 0xD8, 0xFF, 0xDA, 0, 2]), None);
+        // Segments and chunks longer than the file.
+        assert_eq!(
+            exif_orientation(&[0xFF, 0xD8, 0xFF, 0xE1, 0xFF, 0xFF]),
+            None
+        );
+        assert_eq!(exif_orientation(&[0xFF, 0xD8, 0xFF, 0xE1, 0, 0]), None);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(u32::MAX.to_be_bytes());
+        png.extend(b"eXIf");
+        png.extend([0; 4]);
+        assert_eq!(exif_orientation(&png), None);
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend(b"EXIF");
+        webp.extend(u32::MAX.to_le_bytes());
+        assert_eq!(exif_orientation(&webp), None);
+
+        // An IFD past the end, and one that is cut off.
+        let mut tiff = tiff(false, 6);
+        assert_eq!(exif_orientation(&tiff[..tiff.len() - 4]), None);
+        tiff[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(exif_orientation(&tiff), None);
+    }
+
+    #[test]
+    fn turns_images_upright() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(3, 2));
+        for orientation in [0, 1, 2, 3, 4, 9] {
+            let oriented = orient(image.clone(), orientation);
+            assert_eq!((oriented.width(), oriented.height()), (3, 2));
+        }
+        for orientation in 5..=8 {
+            let oriented = orient(image.clone(), orientation);
+            assert_eq!((oriented.width(), oriented.height()), (2, 3));
+        }
+
+        let mut jpeg = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let mut rotated = jpeg_exif(&tiff(false, 6));
+        rotated.extend(&jpeg[2..]);
+        let prepared = prepare(&rotated, 4096, 1_000_000).unwrap();
+        assert_eq!((prepared.width(), prepared.height()), (2, 3));
+    }
+}
@@ -0,0 +1,882 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use image::{DynamicImage, RgbaImage};
+use maud::{html, Markup};
+use resvg::{
+    tiny_skia::{
+        FillRule, Paint, Path, PathBuilder, PathSegment, Pixmap, PixmapPaint, Rect, Transform,
+    },
+    usvg::fontdb,
+};
+use serde::Deserialize;
+use syntect::highlighting::{Color, FontStyle, Style, Theme};
+use ttf_parser::{Face, GlyphId, OutlineBuilder, RasterImageFormat};
+use unicode_width::UnicodeWidthChar;
+
+use crate::{errors::QueryError, fonts};
+
+/// Matches silicon's frame, so that switching renderers doesn't move things around.
+const CODE_PADDING: f32 = 25.0;
+const TITLE_BAR_HEIGHT: f32 = 50.0;
+const TITLE_BAR_CENTER: f32 = 35.0;
+const MIN_WIDTH: f32 = 150.0;
+const CORNER_RADIUS: f32 = 12.0;
+const TITLE_FONT_SIZE: f32 = 20.0;
+const WINDOW_CONTROLS: &[(Color, Color)] = &[
+    (rgb(0xFF5F56), rgb(0xE0443E)),
+    (rgb(0xFFBD2E), rgb(0xDEA123)),
+    (rgb(0x27C93F), rgb(0x1AAB29)),
+];
+const WINDOW_CONTROLS_WIDTH: f32 = 150.0;
+
+const fn rgb(hex: u32) -> Color {
+    Color {
+        r: (hex >> 16) as u8,
+        g: (hex >> 8) as u8,
+        b: hex as u8,
+        a: 0xFF,
+    }
+}
+
+/// How text is written in SVG output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SvgText {
+    /// Real text, which can be selected and searched, but which is drawn with whatever fonts the
+    /// viewer has.
+    #[default]
+    Text,
+    /// The outlines of the glyphs, which look the same everywhere.
+    Outlines,
+}
+
+/// Everything about the window besides the code.
+pub(crate) struct Frame<'a> {
+    /// A fallback chain of font families. The first one sets the size of the columns.
+    pub(crate) fonts: &'a [&'a str],
+    pub(crate) font_size: f32,
+    pub(crate) line_pad: u32,
+    pub(crate) title: Option<&'a str>,
+    pub(crate) window_controls: bool,
+    /// Rows to draw a background band behind, counted from 1 like silicon does.
+    pub(crate) highlighted_rows: &'a [u32],
+}
+
+/// Draws code with tiny-skia, laying out every character on a grid of columns.
+pub(crate) struct NativeRenderer {
+    db: fontdb::Database,
+    /// Fonts are read from disk the first time they're used, and kept around after that.
+    face_data: Mutex<HashMap<fontdb::ID, Arc<Vec<u8>>>>,
+}
+
+impl Default for NativeRenderer {
+    fn default() -> Self {
+        let mut db = fontdb::Database::new();
+        for font in fonts::BUNDLED_FONTS {
+            db.load_font_data(font.to_vec());
+        }
+        db.load_system_fonts();
+        Self {
+            db,
+            face_data: Mutex::default(),
+        }
+    }
+}
+
+/// The data of a font file, and the index of a face in it.
+type FaceData = (Arc<Vec<u8>>, u32);
+
+/// The faces of a family for each of syntect's font styles.
+struct Family<'a> {
+    regular: Face<'a>,
+    bold: Option<Face<'a>>,
+    italic: Option<Face<'a>>,
+    bold_italic: Option<Face<'a>>,
+}
+
+impl<'a> Family<'a> {
+    fn face(&self, style: FontStyle) -> &Face<'a> {
+        let bold = style.contains(FontStyle::BOLD);
+        let italic = style.contains(FontStyle::ITALIC);
+        match (bold, italic) {
+            (true, true) => self.bold_italic.as_ref().or(self.bold.as_ref()),
+            (true, false) => self.bold.as_ref(),
+            (false, true) => self.italic.as_ref(),
+            (false, false) => None,
+        }
+        .unwrap_or(&self.regular)
+    }
+}
+
+impl NativeRenderer {
+    fn load_face(
+        &self,
+        family: &str,
+        weight: fontdb::Weight,
+        style: fontdb::Style,
+    ) -> Option<FaceData> {
+        let id = self.db.query(&fontdb::Query {
+            families: &[fontdb::Family::Name(family)],
+            weight,
+            style,
+            ..Default::default()
+        })?;
+        let (_, index) = self.db.face_source(id)?;
+        let mut face_data = self.face_data.lock().unwrap();
+        if let Some(data) = face_data.get(&id) {
+            return Some((data.clone(), index));
+        }
+        let data = Arc::new(self.db.with_face_data(id, |data, _| data.to_vec())?);
+        face_data.insert(id, data.clone());
+        Some((data, index))
+    }
+
+    /// Loads the regular, bold, italic and bold italic faces of each family, in that order.
+    fn load_families(&self, names: &[&str]) -> Vec<[Option<FaceData>; 4]> {
+        use fontdb::{Style::*, Weight};
+        names
+            .iter()
+            .chain(std::iter::once(&fonts::DEFAULT_FONT))
+            .map(|name| {
+                [
+                    (Weight::NORMAL, Normal),
+                    (Weight::BOLD, Normal),
+                    (Weight::NORMAL, Italic),
+                    (Weight::BOLD, Italic),
+                ]
+                .map(|(weight, style)| self.load_face(name, weight, style))
+            })
+            .filter(|faces| faces[0].is_some())
+            .collect()
+    }
+
+    /// The width of a column of code in the first of `fonts` that's installed.
+    pub(crate) fn column_width(&self, fonts: &[&str], font_size: f32) -> f32 {
+        self.load_families(fonts)
+            .first()
+            .and_then(|faces| parse_face(&faces[0]))
+            .map_or(font_size * 0.6, |face| column_width(&face, font_size))
+    }
+
+    /// Lays out the window and draws it on the canvas made by `new_canvas`, which gets the size of
+    /// the window and returns `None` if it can't make a canvas that big.
+    fn draw<C: Canvas>(
+        &self,
+        rows: &[Vec<(Style, &str)>],
+        theme: &Theme,
+        frame: &Frame,
+        new_canvas: impl FnOnce(f32, f32) -> Option<C>,
+    ) -> Option<C> {
+        let data = self.load_families(frame.fonts);
+        let families = data
+            .iter()
+            .filter_map(|faces| {
+                Some(Family {
+                    regular: parse_face(&faces[0])?,
+                    bold: parse_face(&faces[1]),
+                    italic: parse_face(&faces[2]),
+                    bold_italic: parse_face(&faces[3]),
+                })
+            })
+            .collect::<Vec<_>>();
+        // Hack is bundled, so there's always at least one family.
+        let primary = &families[0].regular;
+
+        let scale = frame.font_size / primary.units_per_em() as f32;
+        let column = column_width(primary, frame.font_size);
+        let ascent = primary.ascender() as f32 * scale;
+        let line_height = ((primary.ascender() - primary.descender()) as f32 * scale).ceil()
+            + frame.line_pad as f32;
+
+        let title_bar = frame.window_controls || frame.title.is_some();
+        let top = CODE_PADDING + if title_bar { TITLE_BAR_HEIGHT } else { 0.0 };
+        let columns = rows
+            .iter()
+            .map(|tokens| {
+                tokens
+                    .iter()
+                    .flat_map(|(_, text)| text.chars())
+                    .filter_map(UnicodeWidthChar::width)
+                    .sum::<usize>()
+            })
+            .max()
+            .unwrap_or(0);
+        let width = (CODE_PADDING * 2.0 + columns as f32 * column)
+            .max(MIN_WIDTH)
+            .ceil();
+        let height = (top + rows.len() as f32 * line_height + CODE_PADDING).ceil();
+        let mut canvas = new_canvas(width, height)?;
+
+        let foreground = theme.settings.foreground.unwrap_or(Color::WHITE);
+        let background = theme.settings.background.unwrap_or(Color::BLACK);
+        if let Some(path) = rounded_rect(width, height, CORNER_RADIUS) {
+            canvas.fill_path(&path, background);
+        }
+
+        let highlight = Color {
+            r: background.r.saturating_add(40),
+            g: background.g.saturating_add(40),
+            b: background.b.saturating_add(40),
+            a: background.a,
+        };
+        for row in frame.highlighted_rows {
+            let y = top + (*row as f32 - 1.0) * line_height;
+            if let Some(rect) = Rect::from_xywh(0.0, y, width, line_height) {
+                canvas.fill_path(&PathBuilder::from_rect(rect), highlight);
+            }
+        }
+
+        if frame.window_controls {
+            for (i, (fill, outline)) in WINDOW_CONTROLS.iter().enumerate() {
+                let x = 35.0 + 40.0 * i as f32;
+                for (color, radius) in [(outline, 11.0), (fill, 10.0)] {
+                    if let Some(path) = PathBuilder::from_circle(x, TITLE_BAR_CENTER, radius) {
+                        canvas.fill_path(&path, *color);
+                    }
+                }
+            }
+        }
+        if let Some(title) = frame.title {
+            let color = Color {
+                r: foreground.r.saturating_sub(20),
+                g: foreground.g.saturating_sub(20),
+                b: foreground.b.saturating_sub(20),
+                a: foreground.a,
+            };
+            draw_title(
+                &mut canvas,
+                &families,
+                title,
+                color,
+                width,
+                frame.window_controls,
+            );
+        }
+
+        for (i, tokens) in rows.iter().enumerate() {
+            let baseline = top + i as f32 * line_height + ascent;
+            let mut x = CODE_PADDING;
+            for (style, text) in tokens {
+                for c in text.chars() {
+                    let Some(columns) = c.width() else {
+                        continue;
+                    };
+                    let (face, id) = find_glyph(&families, c, style.font_style);
+                    canvas.draw_glyph(&Glyph {
+                        face,
+                        id,
+                        c,
+                        style: style.font_style,
+                        // Combining characters are drawn over the character before them.
+                        x: if columns == 0 { x - column } else { x },
+                        baseline,
+                        cell_width: columns.max(1) as f32 * column,
+                        font_size: frame.font_size,
+                        color: style.foreground,
+                    });
+                    x += columns as f32 * column;
+                }
+            }
+        }
+        Some(canvas)
+    }
+
+    /// Everything is laid out at a scale of 1, and drawn `scale` times as big.
+    pub(crate) fn render(
+        &self,
+        rows: &[Vec<(Style, &str)>],
+        theme: &Theme,
+        frame: &Frame,
+        scale: f32,
+    ) -> Result<DynamicImage, QueryError> {
+        let canvas = self
+            .draw(rows, theme, frame, |width, height| {
+                Some(PixmapCanvas {
+                    pixmap: Pixmap::new(
+                        (width * scale).ceil() as u32,
+                        (height * scale).ceil() as u32,
+                    )?,
+                    transform: Transform::from_scale(scale, scale),
+                })
+            })
+            .ok_or_else(|| QueryError::new("The image would be too big"))?;
+        Ok(pixmap_to_image(&canvas.pixmap))
+    }
+
+    pub(crate) fn render_svg(
+        &self,
+        rows: &[Vec<(Style, &str)>],
+        theme: &Theme,
+        frame: &Frame,
+        text: SvgText,
+    ) -> SvgWindow {
+        let font_family = frame
+            .fonts
+            .iter()
+            .map(|font| format!("'{}'", font))
+            .chain(std::iter::once("monospace".to_owned()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.draw(rows, theme, frame, |width, height| {
+            Some(SvgCanvas {
+                width,
+                height,
+                text,
+                font_family,
+                elements: Vec::new(),
+                run: None,
+            })
+        })
+        // SVGs have no size limit.
+        .unwrap()
+        .finish()
+    }
+}
+
+/// The advance of `0` in `face`, which every character is laid out in multiples of.
+fn column_width(face: &Face, font_size: f32) -> f32 {
+    let scale = font_size / face.units_per_em() as f32;
+    face.glyph_index('0')
+        .and_then(|glyph| face.glyph_hor_advance(glyph))
+        .map_or(font_size * 0.6, |advance| advance as f32 * scale)
+}
+
+pub(crate) fn pixmap_to_image(pixmap: &Pixmap) -> DynamicImage {
+    let mut image = RgbaImage::new(pixmap.width(), pixmap.height());
+    for (pixel, color) in image.pixels_mut().zip(pixmap.pixels()) {
+        let color = color.demultiply();
+        pixel.0 = [color.red(), color.green(), color.blue(), color.alpha()];
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+fn parse_face(face: &Option<FaceData>) -> Option<Face<'_>> {
+    face.as_ref()
+        .and_then(|(data, index)| Face::parse(data, *index).ok())
+}
+
+/// Finds the first family in the chain with a glyph for `c`, or the missing glyph of the first
+/// family when none of them have one.
+fn find_glyph<'a, 'b>(
+    families: &'b [Family<'a>],
+    c: char,
+    style: FontStyle,
+) -> (&'b Face<'a>, GlyphId) {
+    families
+        .iter()
+        .find_map(|family| {
+            let face = family.face(style);
+            face.glyph_index(c).map(|glyph| (face, glyph))
+        })
+        .unwrap_or((families[0].face(style), GlyphId(0)))
+}
+
+/// A glyph in a cell that's `cell_width` wide, with its left edge at `x`.
+struct Glyph<'a> {
+    face: &'a Face<'a>,
+    id: GlyphId,
+    c: char,
+    style: FontStyle,
+    x: f32,
+    baseline: f32,
+    cell_width: f32,
+    font_size: f32,
+    color: Color,
+}
+
+impl Glyph<'_> {
+    /// The scale of the glyph's font units, and where its origin goes so that it's centered in its
+    /// cell. Glyphs that don't fit in their cell are shrunk.
+    fn placement(&self) -> (f32, f32) {
+        let mut scale = self.font_size / self.face.units_per_em() as f32;
+        let advance = self.face.glyph_hor_advance(self.id).unwrap_or(0) as f32 * scale;
+        if advance > self.cell_width {
+            scale *= self.cell_width / advance;
+        }
+        (
+            scale,
+            self.x + (self.cell_width - advance.min(self.cell_width)) / 2.0,
+        )
+    }
+
+    fn outline(&self) -> Option<Path> {
+        let (scale, x) = self.placement();
+        let mut outline = GlyphOutline {
+            builder: PathBuilder::new(),
+            x,
+            y: self.baseline,
+            scale,
+        };
+        self.face.outline_glyph(self.id, &mut outline)?;
+        outline.builder.finish()
+    }
+
+    /// Color emoji fonts have PNG images instead of outlines. Returns the image, and where it goes.
+    fn raster(&self) -> Option<(&[u8], Rect)> {
+        let raster = self
+            .face
+            .glyph_raster_image(self.id, self.font_size.round() as u16)?;
+        if raster.format != RasterImageFormat::PNG {
+            return None;
+        }
+        let (scale, x) = self.placement();
+        let scale = scale * self.face.units_per_em() as f32 / raster.pixels_per_em as f32;
+        let rect = Rect::from_xywh(
+            x + raster.x as f32 * scale,
+            self.baseline - (raster.y as f32 + raster.height as f32) * scale,
+            raster.width as f32 * scale,
+            raster.height as f32 * scale,
+        )?;
+        Some((raster.data, rect))
+    }
+}
+
+/// What the native renderer draws on, so that the same layout can be rasterized or written as SVG.
+trait Canvas {
+    fn fill_path(&mut self, path: &Path, color: Color);
+    fn draw_glyph(&mut self, glyph: &Glyph);
+}
+
+struct PixmapCanvas {
+    pixmap: Pixmap,
+    /// From the layout to the pixmap.
+    transform: Transform,
+}
+
+impl Canvas for PixmapCanvas {
+    fn fill_path(&mut self, path: &Path, color: Color) {
+        self.pixmap
+            .fill_path(path, &paint(color), FillRule::Winding, self.transform, None);
+    }
+
+    fn draw_glyph(&mut self, glyph: &Glyph) {
+        if let Some((data, rect)) = glyph.raster() {
+            if let Ok(image) = Pixmap::decode_png(data) {
+                let transform = Transform::from_row(
+                    rect.width() / image.width() as f32,
+                    0.0,
+                    0.0,
+                    rect.height() / image.height() as f32,
+                    rect.x(),
+                    rect.y(),
+                )
+                .post_concat(self.transform);
+                self.pixmap.draw_pixmap(
+                    0,
+                    0,
+                    image.as_ref(),
+                    &PixmapPaint::default(),
+                    transform,
+                    None,
+                );
+            }
+        } else if let Some(path) = glyph.outline() {
+            self.fill_path(&path, glyph.color);
+        }
+    }
+}
+
+/// A window drawn as an SVG document.
+pub(crate) struct SvgWindow {
+    pub(crate) width: f32,
+    pub(crate) height: f32,
+    pub(crate) markup: Markup,
+}
+
+struct SvgCanvas {
+    width: f32,
+    height: f32,
+    text: SvgText,
+    font_family: String,
+    elements: Vec<Markup>,
+    /// Consecutive glyphs on the same line with the same style are written as one element.
+    run: Option<SvgRun>,
+}
+
+struct SvgRun {
+    color: Color,
+    style: FontStyle,
+    font_size: f32,
+    baseline: f32,
+    /// The position of each character of `text`, which keeps them on the grid whatever font the
+    /// viewer has.
+    xs: Vec<String>,
+    text: String,
+    /// The outlines of the glyphs.
+    path: String,
+}
+
+impl SvgCanvas {
+    fn flush(&mut self) {
+        let Some(run) = self.run.take() else {
+            return;
+        };
+        let (fill, fill_opacity) = (svg_color(run.color), svg_opacity(run.color));
+        self.elements.push(match self.text {
+            SvgText::Text => html! {
+                text x=(run.xs.join(" ")) y=(svg_number(run.baseline)) fill=(fill)
+                    fill-opacity=[fill_opacity] font-size=(svg_number(run.font_size))
+                    font-weight=[run.style.contains(FontStyle::BOLD).then_some("bold")]
+                    font-style=[run.style.contains(FontStyle::ITALIC).then_some("italic")] {
+                    (run.text)
+                }
+            },
+            SvgText::Outlines if run.path.is_empty() => return,
+            SvgText::Outlines => html! {
+                path d=(run.path) fill=(fill) fill-opacity=[fill_opacity] {}
+            },
+        });
+    }
+
+    fn finish(mut self) -> SvgWindow {
+        self.flush();
+        let markup = html! {
+            svg xmlns="http://www.w3.org/2000/svg" width=(svg_number(self.width))
+                height=(svg_number(self.height))
+                viewBox={ "0 0 " (svg_number(self.width)) " " (svg_number(self.height)) }
+                font-family=(self.font_family) xml:space="preserve" {
+                @for element in &self.elements {
+                    (element)
+                }
+            }
+        };
+        SvgWindow {
+            width: self.width,
+            height: self.height,
+            markup,
+        }
+    }
+}
+
+impl Canvas for SvgCanvas {
+    fn fill_path(&mut self, path: &Path, color: Color) {
+        self.flush();
+        self.elements.push(html! {
+            path d=(svg_path(path)) fill=(svg_color(color)) fill-opacity=[svg_opacity(color)] {}
+        });
+    }
+
+    fn draw_glyph(&mut self, glyph: &Glyph) {
+        if self.text == SvgText::Outlines {
+            if let Some((data, rect)) = glyph.raster() {
+                self.flush();
+                self.elements.push(html! {
+                    image x=(svg_number(rect.x())) y=(svg_number(rect.y()))
+                        width=(svg_number(rect.width())) height=(svg_number(rect.height()))
+                        href={ "data:image/png;base64," (BASE64.encode(data)) } {}
+                });
+                return;
+            }
+        }
+
+        let continues = self.run.as_ref().is_some_and(|run| {
+            run.color == glyph.color
+                && run.style == glyph.style
+                && run.font_size == glyph.font_size
+                && run.baseline == glyph.baseline
+        });
+        if !continues {
+            self.flush();
+        }
+        let run = self.run.get_or_insert_with(|| SvgRun {
+            color: glyph.color,
+            style: glyph.style,
+            font_size: glyph.font_size,
+            baseline: glyph.baseline,
+            xs: Vec::new(),
+            text: String::new(),
+            path: String::new(),
+        });
+        match self.text {
+            SvgText::Text => {
+                run.text.push(glyph.c);
+                // A character without a position follows the one before it, which is where
+                // combining characters go. Positions only apply to the first characters, so the
+                // run has to end here.
+                if glyph.c.width() == Some(0) {
+                    self.flush();
+                } else {
+                    run.xs.push(svg_number(glyph.x));
+                }
+            }
+            SvgText::Outlines => {
+                if let Some(path) = glyph.outline() {
+                    run.path.push_str(&svg_path(&path));
+                }
+            }
+        }
+    }
+}
+
+/// Draws `title` centered in the title bar, or right after the window controls when the window is
+/// too narrow to center it.
+fn draw_title(
+    canvas: &mut impl Canvas,
+    families: &[Family],
+    title: &str,
+    color: Color,
+    width: f32,
+    window_controls: bool,
+) {
+    let glyphs = title
+        .chars()
+        .map(|c| {
+            let (face, glyph) = find_glyph(families, c, FontStyle::empty());
+            let advance = face.glyph_hor_advance(glyph).unwrap_or(0) as f32 * TITLE_FONT_SIZE
+                / face.units_per_em() as f32;
+            (c, face, glyph, advance)
+        })
+        .collect::<Vec<_>>();
+    let ellipsis = find_glyph(families, '…', FontStyle::empty());
+    let ellipsis_advance = ellipsis.0.glyph_hor_advance(ellipsis.1).unwrap_or(0) as f32
+        * TITLE_FONT_SIZE
+        / ellipsis.0.units_per_em() as f32;
+
+    let left = if window_controls {
+        WINDOW_CONTROLS_WIDTH
+    } else {
+        CODE_PADDING
+    };
+    let available = width - left - CODE_PADDING;
+    let mut count = glyphs.len();
+    let mut text_width = glyphs.iter().map(|(_, _, _, advance)| advance).sum::<f32>();
+    let truncated = text_width > available;
+    if truncated {
+        while count > 0 && text_width + ellipsis_advance > available {
+            count -= 1;
+            text_width -= glyphs[count].3;
+        }
+        if count == 0 {
+            return;
+        }
+        text_width += ellipsis_advance;
+    }
+
+    let primary = &families[0].regular;
+    let scale = TITLE_FONT_SIZE / primary.units_per_em() as f32;
+    let baseline =
+        TITLE_BAR_CENTER + (primary.ascender() + primary.descender()) as f32 * scale / 2.0;
+    let mut x = ((width - text_width) / 2.0).max(left);
+    let ellipsis = truncated.then_some(('…', ellipsis.0, ellipsis.1, ellipsis_advance));
+    for (c, face, id, advance) in glyphs[..count].iter().copied().chain(ellipsis) {
+        canvas.draw_glyph(&Glyph {
+            face,
+            id,
+            c,
+            style: FontStyle::empty(),
+            x,
+            baseline,
+            cell_width: advance,
+            font_size: TITLE_FONT_SIZE,
+            color,
+        });
+        x += advance;
+    }
+}
+
+fn paint(color: Color) -> Paint<'static> {
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(color.r, color.g, color.b, color.a);
+    paint.anti_alias = true;
+    paint
+}
+
+/// Formats a number for an SVG attribute, to a precision that's plenty for pixels.
+pub(crate) fn svg_number(value: f32) -> String {
+    let number = format!("{:.2}", value);
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    if number == "-0" {
+        "0".to_owned()
+    } else {
+        number.to_owned()
+    }
+}
+
+pub(crate) fn svg_color(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+/// The opacity to go with [`svg_color`], if the color isn't opaque.
+pub(crate) fn svg_opacity(color: Color) -> Option<String> {
+    (color.a != 0xFF).then(|| svg_number(color.a as f32 / 255.0))
+}
+
+fn svg_path(path: &Path) -> String {
+    let mut data = String::new();
+    for segment in path.segments() {
+        let (command, points) = match segment {
+            PathSegment::MoveTo(p) => ("M", vec![p]),
+            PathSegment::LineTo(p) => ("L", vec![p]),
+            PathSegment::QuadTo(p1, p) => ("Q", vec![p1, p]),
+            PathSegment::CubicTo(p1, p2, p) => ("C", vec![p1, p2, p]),
+            PathSegment::Close => ("Z", vec![]),
+        };
+        data.push_str(command);
+        let points = points
+            .iter()
+            .map(|p| format!("{} {}", svg_number(p.x), svg_number(p.y)))
+            .collect::<Vec<_>>();
+        data.push_str(&points.join(" "));
+    }
+    data
+}
+
+fn rounded_rect(width: f32, height: f32, radius: f32) -> Option<Path> {
+    // The distance of the control points of a cubic that approximates a quarter circle.
+    let k = radius * 0.552_284_8;
+    let mut builder = PathBuilder::new();
+    builder.move_to(radius, 0.0);
+    builder.line_to(width - radius, 0.0);
+    builder.cubic_to(width - radius + k, 0.0, width, radius - k, width, radius);
+    builder.line_to(width, height - radius);
+    builder.cubic_to(
+        width,
+        height - radius + k,
+        width - radius + k,
+        height,
+        width - radius,
+        height,
+    );
+    builder.line_to(radius, height);
+    builder.cubic_to(
+        radius - k,
+        height,
+        0.0,
+        height - radius + k,
+        0.0,
+        height - radius,
+    );
+    builder.line_to(0.0, radius);
+    builder.cubic_to(0.0, radius - k, radius - k, 0.0, radius, 0.0);
+    builder.close();
+    builder.finish()
+}
+
+/// Turns a glyph's outline into a path, scaled and moved to its place on the pixmap.
+struct GlyphOutline {
+    builder: PathBuilder,
+    x: f32,
+    y: f32,
+    scale: f32,
+}
+
+impl GlyphOutline {
+    fn point(&self, x: f32, y: f32) -> (f32, f32) {
+        (self.x + x * self.scale, self.y - y * self.scale)
+    }
+}
+
+impl OutlineBuilder for GlyphOutline {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.point(x, y);
+        self.builder.move_to(x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.point(x, y);
+        self.builder.line_to(x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (x1, y1) = self.point(x1, y1);
+        let (x, y) = self.point(x, y);
+        self.builder.quad_to(x1, y1, x, y);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (x1, y1) = self.point(x1, y1);
+        let (x2, y2) = self.point(x2, y2);
+        let (x, y) = self.point(x, y);
+        self.builder.cubic_to(x1, y1, x2, y2, x, y);
+    }
+
+    fn close(&mut self) {
+        self.builder.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use resvg::usvg::{self, TreeParsing};
+
+    use super::*;
+
+    #[test]
+    fn renders_windows_sized_to_the_code() {
+        let renderer = NativeRenderer::default();
+        let style = Style::default();
+        // Hack lacks ℝ, which is drawn in DejaVu Sans Mono, and the gutter takes up two columns.
+        let rows = [
+            vec![(style, "1 "), (style, "let reals = ℝ; // 实数")],
+            vec![(style, "2 ")],
+        ];
+        let frame = |fonts, window_controls| Frame {
+            fonts,
+            font_size: 14.0,
+            line_pad: 2,
+            title: None,
+            window_controls,
+            highlighted_rows: &[],
+        };
+        let size = |fonts, window_controls, scale| {
+            let image = renderer
+                .render(
+                    &rows,
+                    &Theme::default(),
+                    &frame(fonts, window_controls),
+                    scale,
+                )
+                .unwrap();
+            (image.width(), image.height())
+        };
+
+        // 24 columns of Hack's 8.43 pixels, and rows of 17 + 2 pixels.
+        assert_eq!(size(&["Hack", "DejaVu Sans Mono"], false, 1.0), (253, 88));
+        assert_eq!(size(&["Hack"], false, 1.0), (253, 88));
+        assert_eq!(size(&["Hack", "DejaVu Sans Mono"], true, 1.0), (253, 138));
+        assert_eq!(size(&["Hack", "DejaVu Sans Mono"], true, 2.0), (506, 276));
+        for scale in [0.0, 1e7] {
+            assert!(renderer
+                .render(&rows, &Theme::default(), &frame(&["Hack"], true), scale)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn renders_svg_windows() {
+        let renderer = NativeRenderer::default();
+        let style = Style::default();
+        let rows = [
+            vec![(style, "1 "), (style, "if a < b && c { return; }")],
+            vec![(style, "2 ")],
+        ];
+        let frame = Frame {
+            fonts: &["Hack"],
+            font_size: 14.0,
+            line_pad: 2,
+            title: Some("<main>.rs"),
+            window_controls: true,
+            highlighted_rows: &[1],
+        };
+        for text in [SvgText::Text, SvgText::Outlines] {
+            let window = renderer.render_svg(&rows, &Theme::default(), &frame, text);
+            // 27 columns of Hack's 8.43 pixels, and rows of 17 + 2 pixels under the title bar.
+            assert_eq!((window.width, window.height), (278.0, 138.0));
+            let svg = window.markup.into_string();
+            if text == SvgText::Text {
+                assert!(svg.contains("&lt;main&gt;"), "{}", svg);
+                assert!(
+                    svg.contains("if a &lt; b &amp;&amp; c { return; }"),
+                    "{}",
+                    svg
+                );
+            }
+            let tree = usvg::Tree::from_str(&svg, &usvg::Options::default()).unwrap();
+            assert_eq!((tree.size.width(), tree.size.height()), (278.0, 138.0));
+        }
+    }
+}
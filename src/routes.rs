@@ -1,21 +1,24 @@
-use std::io::Cursor;
-
 use actix_web::{
     get,
     web::{Data, Path, Query},
     HttpRequest, HttpResponse, Responder, Result,
 };
-use futures_util::StreamExt;
-use image::ImageFormat;
 use maud::{html, DOCTYPE};
 use serde::Deserialize;
 
 use crate::{
+    classify::{self, FileKind},
     content::{Content, GistContent, ImageContent, SVGContent, TextContent, VideoContent},
     errors::RequestError,
-    image_generator,
-    utils::{clamp_query_lines, parse_raw_code_uri, parse_raw_gist_code_uri, QueryLines},
-    Options, UA_REGEX,
+    gitattributes::GitAttributesCache,
+    image_generator::{self, CodeImage, Format},
+    raster, renderer,
+    utils::{
+        deserialize_flag, deserialize_line_number, fetch_svg_resources, image_response,
+        parse_raw_code_uri, parse_raw_gist_code_uri, pin_image_format, read_body, read_lines,
+        resolve_query_lines, QueryHighlight, QueryLines,
+    },
+    video, Options, UA_REGEX,
 };
 
 #[derive(Deserialize)]
@@ -29,87 +32,146 @@ pub(crate) struct SrcPath {
 #[derive(Deserialize, Debug)]
 pub(crate) struct ImgQuery {
     pub(crate) lines: Option<QueryLines>,
+    #[serde(default, deserialize_with = "deserialize_line_number")]
+    pub(crate) line: Option<u32>,
+    pub(crate) context: Option<u32>,
+    pub(crate) lang: Option<String>,
     pub(crate) theme: Option<String>,
     pub(crate) font: Option<String>,
     pub(crate) font_size: Option<f32>,
+    pub(crate) highlight: Option<QueryHighlight>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub(crate) focus: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub(crate) dedent: Option<bool>,
+    pub(crate) tab_width: Option<u8>,
+    pub(crate) wrap: Option<usize>,
+    pub(crate) truncate: Option<usize>,
+    pub(crate) fit: Option<image_generator::Fit>,
+    pub(crate) title: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub(crate) window_controls: Option<bool>,
+    pub(crate) pad_x: Option<u32>,
+    pub(crate) pad_y: Option<u32>,
+    pub(crate) bg: Option<String>,
+    pub(crate) shadow_blur: Option<f32>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub(crate) line_numbers: Option<bool>,
+    pub(crate) line_pad: Option<u32>,
+    pub(crate) renderer: Option<image_generator::Renderer>,
+    pub(crate) format: Option<image_generator::Format>,
+    pub(crate) scale: Option<u32>,
+    pub(crate) svg_text: Option<renderer::SvgText>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) svg_fit: Option<image_generator::SvgFit>,
+    pub(crate) padding: Option<u32>,
 }
 
 #[get("/image/{author}/{repository}/{branch}/{path:.*}", name = "gh-image")]
 pub(crate) async fn get_gh_image(
+    req: HttpRequest,
     path: Path<SrcPath>,
     query: Query<ImgQuery>,
     env: Data<Options>,
     text_img_gen: Data<image_generator::TextImageGenerator>,
     svg_img_gen: Data<image_generator::SvgImageGenerator>,
+    gitattributes: Data<GitAttributesCache>,
 ) -> Result<impl Responder> {
-    let code_uri = parse_raw_code_uri(&path.into_inner())?;
+    let code_uri = parse_raw_code_uri(&path)?;
 
     if let Ok(response) = reqwest::get(code_uri.to_string()).await {
         let content_type_string = response
             .headers()
             .get("Content-Type")
             .and_then(|content_type| content_type.to_str().ok())
-            .unwrap_or("");
-        if content_type_string.contains("text/plain") {
-            let lines = clamp_query_lines(
-                &query.lines.to_owned().unwrap_or(QueryLines::default()),
-                env.max_code_lines,
-            );
-            let mut line: u32 = 0;
-            let mut bytes_read: u32 = 0;
-            let mut buffer = Vec::new();
-            let mut body_stream = response.bytes_stream();
-
-            let start = lines.from - 1;
-
-            while let Some(Ok(chunk)) = body_stream.next().await {
-                for byte in chunk {
-                    if byte == b'\n' {
-                        line += 1;
-                    }
-                    bytes_read += 1;
-
-                    if bytes_read >= env.max_download_bytes {
-                        break;
-                    }
-
-                    if line >= start && line < lines.to {
-                        buffer.push(byte);
-                    } else if line >= lines.to {
-                        break;
+            .unwrap_or("")
+            .to_owned();
+        let (head, body) = classify::peek(response.bytes_stream()).await;
+        match classify::classify(&path.path, &content_type_string, &head).kind {
+            FileKind::Text => {
+                let lines = resolve_query_lines(&query, env.max_code_lines)?;
+                let buffer = read_lines(body, &lines, env.max_download_bytes).await;
+                if let Ok(src_code) = std::str::from_utf8(&buffer) {
+                    let attributes = gitattributes.lookup(&path, env.max_download_bytes).await;
+                    match text_img_gen.generate_from_query(
+                        src_code,
+                        &lines,
+                        Some(&path.path),
+                        attributes.language.as_deref(),
+                        &query,
+                    )? {
+                        CodeImage::Raster(image, matte) => {
+                            return Ok(image_response(&req, &query, &env, image, matte).await);
+                        }
+                        CodeImage::Svg(svg) => {
+                            return Ok(HttpResponse::Ok().content_type("image/svg+xml").body(svg));
+                        }
                     }
                 }
             }
-            if let Ok(src_code) = std::str::from_utf8(&buffer) {
-                text_img_gen
-                    .generate_from_query(src_code, &query)
-                    .write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)
-                    .unwrap();
-                return Ok(HttpResponse::Ok().content_type("image/png").body(buffer));
+            FileKind::Svg => {
+                let buffer = read_body(body, env.max_download_bytes).await;
+                let resources = fetch_svg_resources(&path, &buffer, &svg_img_gen, &env).await;
+                if let Some(image) = svg_img_gen.generate_from_query(&buffer, resources, &query)? {
+                    return Ok(
+                        image_response(&req, &query, &env, image, image_generator::WHITE).await,
+                    );
+                }
             }
-        } else if content_type_string.contains("image/svg+xml") {
-            let mut bytes_read: u32 = 0;
-            let mut body_stream = response.bytes_stream();
-            let mut buffer = Vec::new();
+            _ => {}
+        }
+    }
 
-            while let Some(Ok(chunk)) = body_stream.next().await {
-                for byte in chunk {
-                    buffer.push(byte);
+    Ok(HttpResponse::NotFound().body("Unable to fetch code..."))
+}
 
-                    bytes_read += 1;
+#[derive(Deserialize)]
+pub(crate) struct RasterQuery {
+    pub(crate) format: Option<Format>,
+}
 
-                    if bytes_read >= env.max_download_bytes {
-                        break;
-                    }
-                }
-            }
-            if let Some(image) = svg_img_gen.generate(&buffer) {
-                return Ok(HttpResponse::Ok().content_type("image/png").body(image));
+/// A raster image from a repository, shrunk and re-encoded for crawlers that can't show it as it
+/// is.
+#[get("/raster/{author}/{repository}/{branch}/{path:.*}", name = "gh-raster")]
+pub(crate) async fn get_gh_raster(
+    path: Path<SrcPath>,
+    query: Query<RasterQuery>,
+    env: Data<Options>,
+) -> Result<impl Responder> {
+    let image_uri = parse_raw_code_uri(&path)?;
+
+    if let Ok(response) = reqwest::get(image_uri.to_string()).await {
+        let content_type_string = response
+            .headers()
+            .get("Content-Type")
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("")
+            .to_owned();
+        let (head, body) = classify::peek(response.bytes_stream()).await;
+        let file_type = classify::classify(&path.path, &content_type_string, &head);
+        if file_type.kind == FileKind::RasterImage {
+            let format = query
+                .format
+                .map_or_else(|| raster::default_format(&file_type.mime), Format::raster);
+            let buffer = read_body(body, env.max_download_bytes).await;
+            if let Ok(image) =
+                raster::prepare(&buffer, env.raster_max_dimension, env.max_image_pixels)
+            {
+                return Ok(HttpResponse::Ok().content_type(format.mime_type()).body(
+                    image_generator::encode_image(
+                        image,
+                        format,
+                        image_generator::WHITE,
+                        env.png_optimization.as_ref(),
+                    )
+                    .await,
+                ));
             }
         }
     }
 
-    Ok(HttpResponse::NotFound().body("Unable to fetch code..."))
+    Ok(HttpResponse::NotFound().body("Unable to fetch image..."))
 }
 
 #[get(
@@ -133,9 +195,9 @@ pub(crate) async fn get_gh_video_embed(path: Path<SrcPath>) -> Result<impl Respo
         }
     };
 
-    return Ok(HttpResponse::Ok()
+    Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(html.into_string()));
+        .body(html.into_string()))
 }
 
 #[get("/{author}/{repository}/blob/{branch}/{path:.*}", name = "gh-og")]
@@ -144,6 +206,8 @@ pub(crate) async fn get_gh_open_graph(
     path: Path<SrcPath>,
     query: Query<ImgQuery>,
     env: Data<Options>,
+    gitattributes: Data<GitAttributesCache>,
+    svg_img_gen: Data<image_generator::SvgImageGenerator>,
 ) -> Result<impl Responder> {
     let gh_url = format!("https://github.com{}", req.uri());
     let canon_url = format!("{}{}", env.origin, req.uri());
@@ -157,60 +221,81 @@ pub(crate) async fn get_gh_open_graph(
     {
         if UA_REGEX.is_match(&user_agent_string.to_lowercase()) {
             let code_uri = parse_raw_code_uri(path.as_ref())?;
-            let request = reqwest::Client::new()
-                .head(code_uri.to_string())
-                .send()
+            let file = classify::fetch_file_type(&code_uri.to_string(), &path.path)
                 .await
                 .map_err(RequestError::from)?;
-            let content_type_string = request
-                .headers()
-                .get("Content-Type")
-                .and_then(|content_type| content_type.to_str().ok())
-                .unwrap_or("");
-
-            println!("Content-Type: {}", content_type_string);
-
-            let wrapped_injected_elements = if content_type_string.contains("text/plain") {
-                let lines = clamp_query_lines(
-                    &query.lines.to_owned().unwrap_or(QueryLines::default()),
-                    env.max_code_lines,
-                );
-                let content = TextContent {
-                    path: path.as_ref(),
-                    query_string: req.query_string().to_owned(),
-                    lines,
-                    origin: env.origin.clone(),
-                };
-                Some(content.get_html())
-            } else if content_type_string.contains("image/png")
-                || content_type_string.contains("image/jpeg")
-                || content_type_string.contains("image/jpg")
-                || content_type_string.contains("image/gif")
-            {
-                let content = ImageContent {
-                    path: path.as_ref(),
-                    image_url: code_uri.to_string(),
-                    mime: content_type_string.to_owned(),
-                };
-                Some(content.get_html())
-            } else if content_type_string.contains("image/svg+xml") {
-                let content = SVGContent {
-                    path: path.as_ref(),
-                    origin: env.origin.clone(),
-                };
-                Some(content.get_html())
-            } else if content_type_string.contains("video/mp4")
-                || content_type_string.contains("application/octet-stream")
-            {
-                let content = VideoContent {
-                    path: path.as_ref(),
-                    video_url: code_uri.to_string(),
-                    mime: content_type_string.to_owned(),
-                    origin: env.origin.clone(),
-                };
-                Some(content.get_html())
-            } else {
-                None
+
+            println!("File type: {:?}", file.file_type);
+
+            let wrapped_injected_elements = match file.file_type.kind {
+                FileKind::Text => {
+                    let lines = resolve_query_lines(&query, env.max_code_lines)?;
+                    let (query_string, format) = pin_image_format(req.query_string(), &query);
+                    let content = TextContent {
+                        path: path.as_ref(),
+                        query_string,
+                        format,
+                        lines,
+                        attributes: gitattributes.lookup(&path, env.max_download_bytes).await,
+                        image_size: image_generator::code_image_size(&query, format)?,
+                        origin: env.origin.clone(),
+                    };
+                    Some(content.get_html())
+                }
+                FileKind::RasterImage => {
+                    let shown_as_is = raster::CRAWLER_MIME_TYPES
+                        .contains(&file.file_type.mime.as_str())
+                        && file
+                            .size
+                            .is_some_and(|size| size <= env.max_og_image_bytes as u64);
+                    let content = if shown_as_is {
+                        ImageContent {
+                            path: path.as_ref(),
+                            image_url: code_uri.to_string(),
+                            mime: file.file_type.mime.clone(),
+                        }
+                    } else {
+                        ImageContent {
+                            path: path.as_ref(),
+                            image_url: format!(
+                                "{}/raster/{}/{}/{}/{}",
+                                env.origin, path.author, path.repository, path.branch, path.path
+                            ),
+                            mime: raster::default_format(&file.file_type.mime)
+                                .mime_type()
+                                .to_owned(),
+                        }
+                    };
+                    Some(content.get_html())
+                }
+                FileKind::Svg => {
+                    let (query_string, format) = pin_image_format(req.query_string(), &query);
+                    let content = SVGContent {
+                        path: path.as_ref(),
+                        query_string,
+                        format: format.raster(),
+                        image_size: svg_img_gen.image_size(&query)?,
+                        origin: env.origin.clone(),
+                    };
+                    Some(content.get_html())
+                }
+                FileKind::Video => {
+                    let content = VideoContent {
+                        path: path.as_ref(),
+                        video_url: code_uri.to_string(),
+                        mime: file.file_type.mime.clone(),
+                        metadata: video::fetch_metadata(
+                            &code_uri.to_string(),
+                            &file.file_type.mime,
+                            env.max_download_bytes,
+                        )
+                        .await,
+                        origin: env.origin.clone(),
+                    };
+                    Some(content.get_html())
+                }
+                // Crawlers have nothing to show for the rest.
+                _ => None,
             };
 
             if let Some(injected_elements) = wrapped_injected_elements {
@@ -259,6 +344,7 @@ pub(crate) struct GistPath {
 
 #[get("/gist-image/{author}/{id}", name = "gist-image")]
 pub(crate) async fn get_gist_image(
+    req: HttpRequest,
     path: Path<GistPath>,
     query: Query<ImgQuery>,
     env: Data<Options>,
@@ -267,42 +353,30 @@ pub(crate) async fn get_gist_image(
     let code_uri = parse_raw_gist_code_uri(&path.into_inner())?;
 
     if let Ok(response) = reqwest::get(code_uri.to_string()).await {
-        let lines = clamp_query_lines(
-            &query.lines.to_owned().unwrap_or(QueryLines::default()),
-            env.max_code_lines,
-        );
-        let mut line: u32 = 0;
-        let mut bytes_read: u32 = 0;
-        let mut buffer = Vec::new();
-        let mut body_stream = response.bytes_stream();
-
-        let start = lines.from - 1;
-
-        while let Some(Ok(chunk)) = body_stream.next().await {
-            for byte in chunk {
-                if byte == b'\n' {
-                    line += 1;
+        // `/raw` redirects to `/raw/{revision}/{file name}`, which tells us the gist's file name.
+        let file_name = response
+            .url()
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|file_name| file_name.to_owned());
+        let lines = resolve_query_lines(&query, env.max_code_lines)?;
+        let buffer = read_lines(response.bytes_stream(), &lines, env.max_download_bytes).await;
+        if let Ok(src_code) = std::str::from_utf8(&buffer) {
+            match text_img_gen.generate_from_query(
+                src_code,
+                &lines,
+                file_name.as_deref(),
+                None,
+                &query,
+            )? {
+                CodeImage::Raster(image, matte) => {
+                    return Ok(image_response(&req, &query, &env, image, matte).await);
                 }
-                bytes_read += 1;
-
-                if bytes_read >= env.max_download_bytes {
-                    break;
-                }
-
-                if line >= start && line < lines.to {
-                    buffer.push(byte);
-                } else if line >= lines.to {
-                    break;
+                CodeImage::Svg(svg) => {
+                    return Ok(HttpResponse::Ok().content_type("image/svg+xml").body(svg));
                 }
             }
         }
-        if let Ok(src_code) = std::str::from_utf8(&buffer) {
-            text_img_gen
-                .generate_from_query(src_code, &query)
-                .write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)
-                .unwrap();
-            return Ok(HttpResponse::Ok().content_type("image/png").body(buffer));
-        }
     }
 
     Ok(HttpResponse::NotFound().body("Unable to fetch code..."))
@@ -331,13 +405,13 @@ pub(crate) async fn get_gist_open_graph(
         .and_then(|user_agent| user_agent.to_str().ok())
     {
         if UA_REGEX.is_match(&user_agent_string.to_lowercase()) {
+            let (query_string, format) = pin_image_format(req.query_string(), &query);
             let content = GistContent {
                 path: path.as_ref(),
-                query_string: req.query_string().to_owned(),
-                lines: clamp_query_lines(
-                    &query.lines.to_owned().unwrap_or(QueryLines::default()),
-                    env.max_code_lines,
-                ),
+                query_string,
+                format,
+                lines: resolve_query_lines(&query, env.max_code_lines)?,
+                image_size: image_generator::code_image_size(&query, format)?,
                 origin: env.origin.clone(),
             };
 
@@ -377,6 +451,69 @@ pub(crate) async fn get_gist_open_graph(
         .finish())
 }
 
+#[get("/api/themes", name = "api-themes")]
+pub(crate) async fn get_themes(
+    text_img_gen: Data<image_generator::TextImageGenerator>,
+) -> impl Responder {
+    HttpResponse::Ok().json(text_img_gen.theme_names())
+}
+
+#[get("/api/fonts", name = "api-fonts")]
+pub(crate) async fn get_fonts(
+    text_img_gen: Data<image_generator::TextImageGenerator>,
+) -> impl Responder {
+    HttpResponse::Ok().json(text_img_gen.font_names())
+}
+
+/// The snippet that the gallery renders in every theme.
+const GALLERY_SAMPLE: &str = "/image/amydevs/rxgithub/main/src/main.rs";
+const GALLERY_SAMPLE_LINES: &str = "1-15";
+
+#[get("/gallery", name = "gallery")]
+pub(crate) async fn get_gallery(
+    text_img_gen: Data<image_generator::TextImageGenerator>,
+) -> impl Responder {
+    let html = html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { "Themes" }
+                style {
+                    "body { font-family: sans-serif; max-width: 1280px; margin: 0 auto; padding: 1em; }"
+                    "figure { margin: 2em 0; } img { max-width: 100%; }"
+                }
+            }
+            body {
+                h1 { "Themes" }
+                p {
+                    "Add " code { "?theme=" } " with one of these names to an image or Open Graph URL. "
+                    "The fonts that can be used with " code { "?font=" } " are listed in "
+                    a href="/api/fonts" { "/api/fonts" } "."
+                }
+                @for theme in text_img_gen.theme_names() {
+                    figure {
+                        img loading="lazy" alt=(theme) src=(format!(
+                            "{}?{}",
+                            GALLERY_SAMPLE,
+                            form_urlencoded::Serializer::new(String::new())
+                                .append_pair("lines", GALLERY_SAMPLE_LINES)
+                                .append_pair("theme", theme)
+                                .finish()
+                        ));
+                        figcaption { code { (theme) } }
+                    }
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html.into_string())
+}
+
 #[get("/{path:.*}")]
 pub(crate) async fn get_other_pages(req: HttpRequest) -> impl Responder {
     HttpResponse::PermanentRedirect()
@@ -0,0 +1,273 @@
+use regex::Regex;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+lazy_static! {
+    static ref VIM_MODELINE_REGEX: Regex =
+        Regex::new(r"(?:vi|vim|ex)(?:[<=>]?\d+)?:.*?\b(?:ft|filetype|syntax)=([\w+#-]+)").unwrap();
+    static ref EMACS_MODELINE_REGEX: Regex =
+        Regex::new(r"-\*-\s*(?:.*?\bmode:\s*)?([\w+#-]+)\s*(?:;.*?)?-\*-").unwrap();
+}
+
+/// How many lines at the start and end of a snippet are searched for a modeline.
+const MODELINE_SEARCH_LINES: usize = 5;
+
+/// Names that people (and interpreters in shebangs) commonly use for a language, but that are
+/// neither an extension nor the name of a syntax in the bundled syntax set. This includes the
+/// linguist language names used by `linguist-language` in `.gitattributes`.
+const LANGUAGE_ALIASES: &[(&str, &str)] = &[
+    ("shell", "sh"),
+    ("shellscript", "sh"),
+    ("zsh", "sh"),
+    ("golang", "go"),
+    ("node", "js"),
+    ("nodejs", "js"),
+    ("javascript", "js"),
+    ("deno", "ts"),
+    ("typescript", "ts"),
+    ("python", "py"),
+    ("pypy", "py"),
+    ("ruby", "rb"),
+    ("perl", "pl"),
+    ("rust", "rs"),
+    ("csharp", "cs"),
+    ("c#", "cs"),
+    ("cpp", "cpp"),
+    ("objc", "m"),
+    ("make", "Makefile"),
+    ("docker", "Dockerfile"),
+    ("containerfile", "Dockerfile"),
+    ("elisp", "el"),
+    ("emacs-lisp", "el"),
+    ("text", "txt"),
+    ("plain", "txt"),
+    ("plaintext", "txt"),
+    ("objective c", "m"),
+    ("objective c++", "mm"),
+    ("emacs lisp", "el"),
+    ("common lisp", "lisp"),
+    ("json with comments", "json"),
+    ("jupyter notebook", "json"),
+    ("jsx", "jsx"),
+    ("tsx", "tsx"),
+    ("vue", "vue"),
+    ("html+erb", "erb"),
+    ("html+php", "php"),
+    ("html+django", "j2"),
+    ("jinja", "j2"),
+    ("ignore list", "gitignore"),
+    ("git config", "gitconfig"),
+    ("git attributes", "gitattributes"),
+    ("batchfile", "bat"),
+    ("vim script", "vim"),
+    ("viml", "vim"),
+    ("graphql", "graphql"),
+    ("protocol buffer", "proto"),
+    ("hcl", "hcl"),
+    ("unix assembly", "asm"),
+    ("assembly", "asm"),
+    ("dotenv", ".env"),
+];
+
+/// File names that don't match a syntax by name or extension, mapped to a token that does.
+const FILE_NAME_ALIASES: &[(&str, &str)] = &[
+    ("containerfile", "Dockerfile"),
+    ("justfile", "Makefile"),
+    ("go.mod", "txt"),
+    ("go.sum", "txt"),
+    ("flake.lock", "json"),
+    ("package-lock.json", "json"),
+    ("yarn.lock", "yaml"),
+    ("pnpm-lock.yaml", "yaml"),
+    ("license", "txt"),
+    ("readme", "txt"),
+];
+
+fn find_syntax_by_alias_token<'a>(ps: &'a SyntaxSet, token: &str) -> &'a SyntaxReference {
+    ps.find_syntax_by_token(token)
+        .unwrap_or_else(|| ps.find_syntax_plain_text())
+}
+
+/// Looks up a syntax from a user supplied language name, such as `?lang=python` or a modeline's
+/// `ft=python`.
+pub(crate) fn find_syntax_by_language<'a>(
+    ps: &'a SyntaxSet,
+    language: &str,
+) -> Option<&'a SyntaxReference> {
+    let language = language.trim();
+    if language.is_empty() {
+        return None;
+    }
+    // Linguist spells multi-word names with dashes in `.gitattributes`, e.g. `Emacs-Lisp`.
+    let spaced = language.replace('-', " ");
+    ps.find_syntax_by_token(language)
+        .or_else(|| ps.find_syntax_by_token(&spaced))
+        .or_else(|| {
+            LANGUAGE_ALIASES
+                .iter()
+                .find(|(alias, _)| {
+                    alias.eq_ignore_ascii_case(language) || alias.eq_ignore_ascii_case(&spaced)
+                })
+                .map(|(_, token)| find_syntax_by_alias_token(ps, token))
+        })
+}
+
+/// Looks up a syntax from a file path, first by the exact file name, then by each of its
+/// extensions from the longest to the shortest (`index.html.erb` tries `html.erb` then `erb`).
+pub(crate) fn find_syntax_by_path<'a>(
+    ps: &'a SyntaxSet,
+    path: &str,
+) -> Option<&'a SyntaxReference> {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    if file_name.is_empty() {
+        return None;
+    }
+
+    if let Some(syntax) = ps.find_syntax_by_extension(file_name) {
+        return Some(syntax);
+    }
+    if let Some((_, token)) = FILE_NAME_ALIASES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(file_name))
+    {
+        return Some(find_syntax_by_alias_token(ps, token));
+    }
+    // `Dockerfile.dev`, `Makefile.linux` and friends.
+    if let Some((stem, _)) = file_name.split_once('.') {
+        if !stem.is_empty() {
+            if let Some(syntax) = ps
+                .find_syntax_by_extension(stem)
+                .filter(|syntax| syntax.name == "Dockerfile" || syntax.name == "Makefile")
+            {
+                return Some(syntax);
+            }
+        }
+    }
+
+    file_name
+        .char_indices()
+        .filter(|(i, c)| *c == '.' && *i + 1 < file_name.len())
+        .find_map(|(i, _)| ps.find_syntax_by_extension(&file_name[i + 1..]))
+}
+
+/// Looks up a syntax from a `#!` line, falling back to the interpreter name when none of the
+/// syntaxes' first line patterns match.
+fn find_syntax_by_shebang<'a>(ps: &'a SyntaxSet, code: &str) -> Option<&'a SyntaxReference> {
+    let first_line = code.lines().next()?;
+    if !first_line.starts_with("#!") {
+        return None;
+    }
+    ps.find_syntax_by_first_line(first_line).or_else(|| {
+        let mut words = first_line[2..].split_whitespace();
+        let mut interpreter = words.next()?.rsplit('/').next()?;
+        if interpreter == "env" {
+            interpreter = words.find(|word| !word.starts_with('-'))?;
+        }
+        find_syntax_by_language(
+            ps,
+            interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'),
+        )
+    })
+}
+
+/// Looks up a syntax from a vim (`vim: set ft=python:`) or emacs (`-*- mode: python -*-`)
+/// modeline near the start or the end of the snippet.
+fn find_syntax_by_modeline<'a>(ps: &'a SyntaxSet, code: &str) -> Option<&'a SyntaxReference> {
+    let lines: Vec<&str> = code.lines().collect();
+    let tail_start = lines.len().saturating_sub(MODELINE_SEARCH_LINES);
+    lines
+        .iter()
+        .take(MODELINE_SEARCH_LINES)
+        .chain(lines.iter().skip(tail_start.max(MODELINE_SEARCH_LINES)))
+        .find_map(|line| {
+            VIM_MODELINE_REGEX
+                .captures(line)
+                .or_else(|| EMACS_MODELINE_REGEX.captures(line))
+                .and_then(|captures| find_syntax_by_language(ps, &captures[1]))
+        })
+}
+
+/// Picks the syntax used to highlight `code`.
+///
+/// An explicit language wins, followed by the file name and extension of `path`, a shebang or
+/// first line match, and a modeline. Anything else is highlighted as plain text.
+pub(crate) fn find_syntax<'a>(
+    ps: &'a SyntaxSet,
+    code: &str,
+    path: Option<&str>,
+    language: Option<&str>,
+) -> &'a SyntaxReference {
+    language
+        .and_then(|language| find_syntax_by_language(ps, language))
+        .or_else(|| path.and_then(|path| find_syntax_by_path(ps, path)))
+        .or_else(|| find_syntax_by_shebang(ps, code))
+        .or_else(|| ps.find_syntax_by_first_line(code.lines().next().unwrap_or("")))
+        .or_else(|| find_syntax_by_modeline(ps, code))
+        .unwrap_or_else(|| ps.find_syntax_plain_text())
+}
+
+#[cfg(test)]
+mod tests {
+    use silicon::assets::HighlightingAssets;
+
+    use super::*;
+
+    #[test]
+    fn finds_syntaxes_in_order_of_precedence() {
+        let mut builder = HighlightingAssets::new().syntax_set.into_builder();
+        builder.add_plain_text_syntax();
+        let ps = builder.build();
+        let name = |code, path, language| find_syntax(&ps, code, path, language).name.as_str();
+
+        let python = "#!/usr/bin/env python3\nprint('hi')\n";
+        let modeline = "x = 1\n# vim: set ft=ruby:\n";
+        for (code, path, language, syntax) in [
+            ("FROM alpine", Some("Dockerfile"), None, "Dockerfile"),
+            (
+                "FROM alpine",
+                Some("docker/Dockerfile.dev"),
+                None,
+                "Dockerfile",
+            ),
+            ("all:", Some("Makefile"), None, "Makefile"),
+            ("[[package]]", Some("Cargo.lock"), None, "TOML"),
+            (python, None, None, "Python"),
+            (python, Some("bin/run"), None, "Python"),
+            (
+                "#!/bin/sh -e\necho hi\n",
+                None,
+                None,
+                "Bourne Again Shell (bash)",
+            ),
+            (modeline, None, None, "Ruby"),
+            ("# -*- mode: python -*-\nx = 1\n", None, None, "Python"),
+            ("<?xml version=\"1.0\"?>\n<a/>\n", None, None, "XML"),
+            ("x = 1\n", None, None, "Plain Text"),
+            // ?lang beats the file name.
+            ("x = 1", Some("main.py"), Some("ruby"), "Ruby"),
+            // The file name beats the extension.
+            ("FROM alpine", Some("Dockerfile.rs"), None, "Dockerfile"),
+            // The extension beats a shebang.
+            (python, Some("main.rb"), None, "Ruby"),
+            // A shebang beats a modeline.
+            (
+                "#!/usr/bin/env python3\n# vim: set ft=ruby:\n",
+                None,
+                None,
+                "Python",
+            ),
+            // An unknown ?lang falls back to everything else.
+            ("x = 1", Some("main.rs"), Some("nonsense"), "Rust"),
+            (modeline, None, Some("nonsense"), "Ruby"),
+            ("x = 1", None, Some("nonsense"), "Plain Text"),
+        ] {
+            assert_eq!(
+                name(code, path, language),
+                syntax,
+                "{:?} {:?} {:?}",
+                code,
+                path,
+                language
+            );
+        }
+    }
+}
@@ -1,7 +1,22 @@
-use actix_web::{http::Uri, Result};
+use actix_web::{
+    http::{header, Uri},
+    HttpRequest, HttpResponse, Result,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::{Stream, StreamExt};
 use serde::{de, Deserialize, Deserializer};
 
-use crate::routes::{GistPath, SrcPath};
+use crate::{
+    errors::QueryError,
+    image_generator::{self, Format, SvgImageGenerator, SvgResources},
+    routes::{GistPath, ImgQuery, SrcPath},
+    Options,
+};
 
 // pub(crate) fn parse_blob_code_uri(path: &SrcPath) -> Result<Uri> {
 //     Ok(Uri::builder()
@@ -25,6 +40,17 @@ pub(crate) fn parse_raw_code_uri(path: &SrcPath) -> Result<Uri> {
         .build()?)
 }
 
+pub(crate) fn parse_raw_gitattributes_uri(path: &SrcPath) -> Result<Uri> {
+    Ok(Uri::builder()
+        .scheme("https")
+        .authority("raw.githubusercontent.com")
+        .path_and_query(format!(
+            "/{}/{}/{}/.gitattributes",
+            path.author, path.repository, path.branch
+        ))
+        .build()?)
+}
+
 pub(crate) fn parse_raw_gist_code_uri(path: &GistPath) -> Result<Uri> {
     Ok(Uri::builder()
         .scheme("https")
@@ -33,18 +59,361 @@ pub(crate) fn parse_raw_gist_code_uri(path: &GistPath) -> Result<Uri> {
         .build()?)
 }
 
+/// Reads a whole body, such as `response.bytes_stream()`, stopping after `max_bytes`.
+pub(crate) async fn read_body<B: AsRef<[u8]>, E>(
+    mut body_stream: impl Stream<Item = Result<B, E>> + Unpin,
+    max_bytes: u32,
+) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    while let Some(Ok(chunk)) = body_stream.next().await {
+        let chunk = chunk.as_ref();
+        let remaining = max_bytes as usize - buffer.len();
+        if chunk.len() >= remaining {
+            buffer.extend_from_slice(&chunk[..remaining]);
+            break;
+        }
+        buffer.extend_from_slice(chunk);
+    }
+
+    buffer
+}
+
+/// The most images that are downloaded for one SVG.
+const MAX_SVG_IMAGES: usize = 16;
+/// The most font files that are downloaded for one SVG.
+const MAX_SVG_FONTS: usize = 8;
+const FONT_EXTENSIONS: &[&str] = &["ttf", "otf", "ttc"];
+
+/// The URL that the SVG at `path` loads `href` from. Relative paths stay inside the SVG's
+/// repository, and paths starting with `/` are relative to its root. Other URLs have to be on one
+/// of `allowed_hosts`.
+fn resolve_svg_resource_url(
+    path: &SrcPath,
+    href: &str,
+    allowed_hosts: &[String],
+) -> Option<reqwest::Url> {
+    let svg_url = reqwest::Url::parse(&parse_raw_code_uri(path).ok()?.to_string()).ok()?;
+    let root = format!("/{}/{}/{}/", path.author, path.repository, path.branch);
+    let url = match href.strip_prefix('/') {
+        Some(href) if !href.starts_with('/') => svg_url.join(&format!("{}{}", root, href)),
+        _ => svg_url.join(href),
+    }
+    .ok()?;
+
+    let same_repository = url.host_str() == svg_url.host_str() && url.path().starts_with(&root);
+    let allowed_host = url.host_str().is_some_and(|host| {
+        allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    });
+    (matches!(url.scheme(), "http" | "https") && (same_repository || allowed_host)).then_some(url)
+}
+
+/// Downloads what the SVG at `path` refers to: its images, and the font files next to it for the
+/// families that aren't loaded. Everything shares `MAX_DOWNLOAD_BYTES` with the SVG itself, and
+/// whatever doesn't fit is left out.
+pub(crate) async fn fetch_svg_resources(
+    path: &SrcPath,
+    svg: &[u8],
+    svg_img_gen: &SvgImageGenerator,
+    env: &Options,
+) -> SvgResources {
+    let Some(missing) = svg_img_gen.missing_resources(svg) else {
+        return SvgResources::default();
+    };
+    // Redirects could leave the allowed hosts.
+    let Ok(client) = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+    else {
+        return SvgResources::default();
+    };
+    let mut remaining = env.max_download_bytes.saturating_sub(svg.len() as u32);
+
+    let mut images = HashMap::new();
+    for href in missing.image_hrefs.into_iter().take(MAX_SVG_IMAGES) {
+        let Some(url) = resolve_svg_resource_url(path, &href, &env.svg_image_hosts) else {
+            continue;
+        };
+        if let Some(image) = download(&client, url, &mut remaining).await {
+            images.insert(href, Arc::new(image));
+        }
+    }
+
+    let mut fonts = Vec::new();
+    if !missing.font_families.is_empty() {
+        let files = svg_img_gen.directories.list(&client, path).await;
+        for file_name in font_files(&files, &missing.font_families) {
+            let Some(url) = resolve_svg_resource_url(path, &file_name, &[]) else {
+                continue;
+            };
+            if let Some(font) = download(&client, url, &mut remaining).await {
+                fonts.push(font);
+            }
+        }
+    }
+
+    SvgResources { images, fonts }
+}
+
+async fn download(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    remaining: &mut u32,
+) -> Option<Vec<u8>> {
+    let response = client.get(url).send().await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    let body = read_body(response.bytes_stream(), remaining.saturating_add(1)).await;
+    if body.len() > *remaining as usize {
+        return None;
+    }
+    *remaining -= body.len() as u32;
+    Some(body)
+}
+
+#[derive(Deserialize)]
+struct RepositoryFile {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// How long the listing of a directory is reused before GitHub's API is asked for it again.
+const DIRECTORY_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+const DIRECTORY_CACHE_MAX_ENTRIES: usize = 1024;
+
+type DirectoryCacheEntry = (Instant, Arc<Vec<String>>);
+
+/// Caches the names of the files in each directory of a repository and ref, including directories
+/// that couldn't be listed, since GitHub's API is rate limited.
+#[derive(Default)]
+pub(crate) struct DirectoryCache {
+    entries: Mutex<HashMap<String, DirectoryCacheEntry>>,
+}
+
+impl DirectoryCache {
+    /// The names of the files in the directory of `path`.
+    async fn list(&self, client: &reqwest::Client, path: &SrcPath) -> Arc<Vec<String>> {
+        let dir = path.path.rsplit_once('/').map_or("", |(dir, _)| dir);
+        let key = format!(
+            "{}/{}@{}/{}",
+            path.author, path.repository, path.branch, dir
+        );
+
+        if let Some((fetched_at, files)) = self.entries.lock().unwrap().get(&key) {
+            if fetched_at.elapsed() < DIRECTORY_CACHE_TTL {
+                return files.clone();
+            }
+        }
+
+        let files = Arc::new(Self::fetch(client, path, dir).await);
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= DIRECTORY_CACHE_MAX_ENTRIES {
+            entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < DIRECTORY_CACHE_TTL);
+            if entries.len() >= DIRECTORY_CACHE_MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (Instant::now(), files.clone()));
+
+        files
+    }
+
+    async fn fetch(client: &reqwest::Client, path: &SrcPath, dir: &str) -> Vec<String> {
+        let Ok(mut url) = reqwest::Url::parse("https://api.github.com") else {
+            return Vec::new();
+        };
+        url.set_path(&format!(
+            "/repos/{}/{}/contents/{}",
+            path.author, path.repository, dir
+        ));
+        url.query_pairs_mut().append_pair("ref", &path.branch);
+        let Ok(response) = client
+            .get(url)
+            .header(header::USER_AGENT.as_str(), "rxgithub")
+            .send()
+            .await
+        else {
+            return Vec::new();
+        };
+        response
+            .json::<Vec<RepositoryFile>>()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|file| file.kind == "file")
+            .map(|file| file.name)
+            .collect()
+    }
+}
+
+/// The names among `files` that look like font files of one of `families`, such as
+/// `FiraCode-Bold.ttf` for `Fira Code`.
+fn font_files(files: &[String], families: &[String]) -> Vec<String> {
+    // Lowercase letters and digits only, since file names leave out spaces or swap them for
+    // dashes.
+    let normalize = |name: &str| {
+        name.chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<String>()
+    };
+    let families = families
+        .iter()
+        .map(|family| normalize(family))
+        .filter(|family| !family.is_empty())
+        .collect::<Vec<_>>();
+
+    files
+        .iter()
+        .filter(|name| {
+            name.rsplit_once('.').is_some_and(|(stem, extension)| {
+                let stem = normalize(stem);
+                FONT_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+                    && families.iter().any(|family| stem.starts_with(family))
+            })
+        })
+        .take(MAX_SVG_FONTS)
+        .cloned()
+        .collect()
+}
+
+/// Reads the lines in `lines` from a body, without the trailing newline, stopping after
+/// `max_bytes`.
+pub(crate) async fn read_lines<B: AsRef<[u8]>, E>(
+    mut body_stream: impl Stream<Item = Result<B, E>> + Unpin,
+    lines: &Lines,
+    max_bytes: u32,
+) -> Vec<u8> {
+    let mut line: u32 = 1;
+    let mut bytes_read: u32 = 0;
+    let mut buffer = Vec::new();
+
+    'stream: while let Some(Ok(chunk)) = body_stream.next().await {
+        for &byte in chunk.as_ref() {
+            bytes_read += 1;
+
+            if bytes_read >= max_bytes || (byte == b'\n' && line >= lines.last()) {
+                break 'stream;
+            }
+
+            if lines.contains(line) {
+                buffer.push(byte);
+            }
+            if byte == b'\n' {
+                line += 1;
+            }
+        }
+    }
+
+    buffer
+}
+
+/// Deserializes a flag such as `?focus=1`, also accepting `true`/`false`, `yes`/`no` and `on`/`off`.
+pub(crate) fn deserialize_flag<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    match value.to_lowercase().as_str() {
+        "" | "1" | "true" | "yes" | "on" => Ok(Some(true)),
+        "0" | "false" | "no" | "off" => Ok(Some(false)),
+        _ => Err(de::Error::invalid_value(
+            de::Unexpected::Str(&value),
+            &"a flag such as 1 or 0",
+        )),
+    }
+}
+
+/// Parses a line number, optionally prefixed with `L` like GitHub's `#L10` anchors.
+fn parse_line_number(line: &str) -> Option<u32> {
+    let line = line.trim();
+    let line = line
+        .strip_prefix('L')
+        .or_else(|| line.strip_prefix('l'))
+        .unwrap_or(line);
+    line.parse::<u32>().ok().filter(|line| *line > 0)
+}
+
+/// Deserializes a single line number such as `?line=142` or `?line=L142`.
+pub(crate) fn deserialize_line_number<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_line_number(&value).map(Some).ok_or_else(|| {
+        de::Error::invalid_value(de::Unexpected::Str(&value), &"a line number such as 142")
+    })
+}
+
+/// Checks that an optional query parameter is within `min..=max`.
+pub(crate) fn check_query_range<T: PartialOrd + std::fmt::Display>(
+    name: &str,
+    value: Option<T>,
+    min: T,
+    max: T,
+) -> Result<Option<T>, QueryError> {
+    let range = min..=max;
+    match value {
+        Some(value) if !range.contains(&value) => Err(QueryError::new(format!(
+            "`{}` must be between {} and {}",
+            name,
+            range.start(),
+            range.end()
+        ))),
+        value => Ok(value),
+    }
+}
+
+/// Parses `10`, `10-20`, `10:20`, `L10-L20` or `10-`, which is open ended.
+fn parse_line_range(range: &str) -> Option<QueryLineRange> {
+    let range = range.trim().trim_start_matches('#');
+    match range.split_once(['-', ':']) {
+        Some((from, "")) => Some(QueryLineRange {
+            from: parse_line_number(from)?,
+            to: None,
+        }),
+        Some((from, to)) => {
+            let (from, to) = (parse_line_number(from)?, parse_line_number(to)?);
+            (from <= to).then_some(QueryLineRange { from, to: Some(to) })
+        }
+        None => {
+            let line = parse_line_number(range)?;
+            Some(QueryLineRange {
+                from: line,
+                to: Some(line),
+            })
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct QueryLines {
+pub(crate) struct QueryLineRange {
     pub(crate) from: u32,
     pub(crate) to: Option<u32>,
 }
 
+/// One or more line ranges, written like `1-5,40-48,90`.
+#[derive(Debug, Clone)]
+pub(crate) struct QueryLines {
+    pub(crate) ranges: Vec<QueryLineRange>,
+}
+
 impl Default for QueryLines {
     fn default() -> Self {
-        Self { from: 1, to: None }
+        Self {
+            ranges: vec![QueryLineRange { from: 1, to: None }],
+        }
     }
 }
 
+/// The number of lines shown on each side of `?line=` when `?context=` isn't given.
+const DEFAULT_CONTEXT_LINES: u32 = 3;
+
 impl<'de> Deserialize<'de> for QueryLines {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -56,32 +425,24 @@ impl<'de> Deserialize<'de> for QueryLines {
             type Value = QueryLines;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("a string in the format 'from-to'")
+                formatter.write_str(
+                    "a comma separated list of lines or ranges, such as '10', 'L10-L20', '10:20' or '10-'",
+                )
             }
 
             fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
             where
                 E: de::Error,
             {
-                let parts: Vec<&str> = value.split('-').collect();
-
-                if parts.is_empty() {
-                    return Err(E::custom("invalid format"));
-                }
-
-                let from: u32 = parts
-                    .first()
-                    .and_then(|from| from.parse().ok())
-                    .unwrap_or(1);
-                let mut to: Option<u32> = parts.get(1).and_then(|to| to.parse().ok());
+                let ranges = value
+                    .split(',')
+                    .map(|range| {
+                        parse_line_range(range)
+                            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+                    })
+                    .collect::<Result<Vec<_>, E>>()?;
 
-                if let Some(to_unwrapped) = to {
-                    if to_unwrapped < from {
-                        to = None;
-                    }
-                }
-
-                Ok(QueryLines { from, to })
+                Ok(QueryLines { ranges })
             }
         }
 
@@ -89,22 +450,417 @@ impl<'de> Deserialize<'de> for QueryLines {
     }
 }
 
-pub(crate) struct Lines {
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LineRange {
     pub(crate) from: u32,
     pub(crate) to: u32,
 }
 
+/// Sorted, non-overlapping line ranges.
+#[derive(Debug, Clone)]
+pub(crate) struct Lines {
+    pub(crate) ranges: Vec<LineRange>,
+    /// The line and the number of lines on each side, when the lines were picked with `?line=`.
+    pub(crate) around: Option<(u32, u32)>,
+}
+
+impl Lines {
+    pub(crate) fn contains(&self, line: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|range| (range.from..=range.to).contains(&line))
+    }
+
+    pub(crate) fn last(&self) -> u32 {
+        self.ranges.last().map(|range| range.to).unwrap_or(0)
+    }
+
+    /// Every line number in the ranges, in order.
+    pub(crate) fn numbers(&self) -> impl Iterator<Item = u32> + '_ {
+        self.ranges.iter().flat_map(|range| range.from..=range.to)
+    }
+}
+
+impl std::fmt::Display for Lines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some((line, context)) = self.around {
+            return write!(f, "Line {} (±{})", line, context);
+        }
+        if let [range] = self.ranges.as_slice() {
+            if range.from == range.to {
+                return write!(f, "Line {}", range.from);
+            }
+        }
+        f.write_str("Lines ")?;
+        for (i, range) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            if range.from == range.to {
+                write!(f, "{}", range.from)?;
+            } else {
+                write!(f, "{}-{}", range.from, range.to)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sorts and merges the ranges, and cuts them down to `max_code_lines` lines in total. A range
+/// without an end takes up the rest of the lines.
 pub(crate) fn clamp_query_lines(lines: &QueryLines, max_code_lines: u32) -> Lines {
-    if let Some(to) = lines.to {
-        if (to - lines.from) < max_code_lines {
-            return Lines {
-                from: lines.from,
-                to,
-            };
+    let mut query_ranges = lines.ranges.clone();
+    query_ranges.sort_by_key(|range| range.from);
+
+    let mut merged: Vec<QueryLineRange> = Vec::new();
+    for range in query_ranges {
+        match merged.last_mut() {
+            Some(last) if last.to.is_none_or(|to| range.from <= to.saturating_add(1)) => {
+                last.to = last.to.zip(range.to).map(|(a, b)| a.max(b));
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    let mut remaining = max_code_lines;
+    let mut ranges = Vec::new();
+    for range in merged {
+        if remaining == 0 {
+            break;
         }
+        let limit = range.from.saturating_add(remaining - 1);
+        let to = range.to.map_or(limit, |to| to.min(limit));
+        remaining -= to - range.from + 1;
+        ranges.push(LineRange {
+            from: range.from,
+            to,
+        });
     }
+
     Lines {
-        from: lines.from,
-        to: lines.from + max_code_lines - 1,
+        ranges,
+        around: None,
+    }
+}
+
+/// Responds with a raster image in the format picked with `?format=`, or else in the best one that
+/// the client accepts. Formats without transparency put the image on `matte`.
+pub(crate) async fn image_response(
+    req: &HttpRequest,
+    query: &ImgQuery,
+    env: &Options,
+    image: image::DynamicImage,
+    matte: image::Rgba<u8>,
+) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    let format = match query.format {
+        Some(format) => format.raster(),
+        None => {
+            response.insert_header((header::VARY, "Accept"));
+            req.headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .map_or(Format::Png, Format::negotiate)
+        }
+    };
+    response.content_type(format.mime_type()).body(
+        image_generator::encode_image(image, format, matte, env.png_optimization.as_ref()).await,
+    )
+}
+
+/// The query string of the image in an Open Graph page, and the format the image comes in. The
+/// format is pinned so that `og:image:type` stays right whatever the crawler accepts.
+pub(crate) fn pin_image_format(query_string: &str, query: &ImgQuery) -> (String, Format) {
+    match query.format {
+        Some(format) => (query_string.to_owned(), format),
+        None if query_string.is_empty() => ("format=png".to_owned(), Format::Png),
+        None => (format!("{}&format=png", query_string), Format::Png),
+    }
+}
+
+/// Picks the lines to show from `?line=` and `?context=`, or from `?lines=` otherwise.
+pub(crate) fn resolve_query_lines(
+    query: &ImgQuery,
+    max_code_lines: u32,
+) -> Result<Lines, QueryError> {
+    Ok(match query.line {
+        Some(line) => {
+            let context = query
+                .context
+                .unwrap_or(DEFAULT_CONTEXT_LINES)
+                .min(max_code_lines.saturating_sub(1) / 2);
+            let mut lines = clamp_query_lines(
+                &QueryLines {
+                    ranges: vec![QueryLineRange {
+                        from: line.saturating_sub(context).max(1),
+                        to: Some(line.saturating_add(context)),
+                    }],
+                },
+                max_code_lines,
+            );
+            lines.around = Some((line, context));
+            lines
+        }
+        None if query.context.is_some() => {
+            return Err(QueryError::new("`context` only works with `line`"));
+        }
+        None => clamp_query_lines(&query.lines.to_owned().unwrap_or_default(), max_code_lines),
+    })
+}
+
+/// A set of absolute line numbers, written like `12,15-17`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct QueryHighlight {
+    ranges: Vec<QueryLineRange>,
+}
+
+impl QueryHighlight {
+    pub(crate) fn contains(&self, line: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|range| (range.from..=range.to.unwrap_or(u32::MAX)).contains(&line))
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryHighlight {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct QueryHighlightVisitor;
+
+        impl<'de> de::Visitor<'de> for QueryHighlightVisitor {
+            type Value = QueryHighlight;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a comma separated list of lines or ranges, such as '12,15-17'")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                let ranges = value
+                    .split(',')
+                    .map(|range| {
+                        parse_line_range(range)
+                            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+                    })
+                    .collect::<Result<Vec<_>, E>>()?;
+
+                Ok(QueryHighlight { ranges })
+            }
+        }
+
+        deserializer.deserialize_str(QueryHighlightVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::value::{Error, StrDeserializer};
+
+    use super::*;
+
+    fn range(range: &str) -> Option<(u32, Option<u32>)> {
+        parse_line_range(range).map(|range| (range.from, range.to))
+    }
+
+    #[test]
+    fn parses_line_ranges() {
+        assert_eq!(range("10"), Some((10, Some(10))));
+        assert_eq!(range("10-20"), Some((10, Some(20))));
+        assert_eq!(range("10:20"), Some((10, Some(20))));
+        assert_eq!(range("L10-L20"), Some((10, Some(20))));
+        assert_eq!(range("#L10"), Some((10, Some(10))));
+        assert_eq!(range(" 10- "), Some((10, None)));
+    }
+
+    #[test]
+    fn rejects_malformed_line_ranges() {
+        for malformed in ["", "0", "-5", "10-5", "a-b", "10-20-30", "L", "99999999999"] {
+            assert_eq!(range(malformed), None, "{:?}", malformed);
+        }
+    }
+
+    fn highlight(value: &str) -> Result<QueryHighlight, Error> {
+        QueryHighlight::deserialize(StrDeserializer::<Error>::new(value))
+    }
+
+    #[test]
+    fn highlights_lines_and_ranges() {
+        let highlight = highlight("12,15-17,L30-").unwrap();
+        for line in [12, 15, 16, 17, 30, 1000] {
+            assert!(highlight.contains(line), "{}", line);
+        }
+        for line in [1, 11, 13, 14, 18, 29] {
+            assert!(!highlight.contains(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_highlights_that_lines_rejects() {
+        for malformed in ["10-5", "0", "12,", "x"] {
+            assert!(highlight(malformed).is_err(), "{:?}", malformed);
+            assert!(
+                QueryLines::deserialize(StrDeserializer::<Error>::new(malformed)).is_err(),
+                "{:?}",
+                malformed
+            );
+        }
+    }
+
+    #[test]
+    fn clamps_query_lines() {
+        let clamp = |lines: &str, max_code_lines| {
+            let lines = QueryLines::deserialize(StrDeserializer::<Error>::new(lines)).unwrap();
+            clamp_query_lines(&lines, max_code_lines)
+                .ranges
+                .iter()
+                .map(|range| (range.from, range.to))
+                .collect::<Vec<_>>()
+        };
+
+        for (lines, max_code_lines, clamped) in [
+            ("5-10", 25, vec![(5, 10)]),
+            ("40-48,1-5", 25, vec![(1, 5), (40, 48)]),
+            // Overlapping and adjacent ranges are merged.
+            ("1-10,5-12", 25, vec![(1, 12)]),
+            ("1-10,11-12", 25, vec![(1, 12)]),
+            ("5-6,1-20,3", 25, vec![(1, 20)]),
+            // Ranges without an end take up the rest of the lines.
+            ("10-", 25, vec![(10, 34)]),
+            ("10-,12-14", 25, vec![(10, 34)]),
+            ("1-3,10-", 25, vec![(1, 3), (10, 31)]),
+            // The lines are cut down to `max_code_lines` in total.
+            ("1-100", 25, vec![(1, 25)]),
+            ("1-20,31-40,51-60", 25, vec![(1, 20), (31, 35)]),
+            ("1-25,31-40", 25, vec![(1, 25)]),
+            ("4294967290-", 25, vec![(4294967290, 4294967295)]),
+        ] {
+            assert_eq!(clamp(lines, max_code_lines), clamped, "{:?}", lines);
+        }
+    }
+
+    fn resolve_lines(query: &str, max_code_lines: u32) -> Result<Lines, QueryError> {
+        let query = actix_web::web::Query::<ImgQuery>::from_query(query).unwrap();
+        resolve_query_lines(&query, max_code_lines)
+    }
+
+    #[test]
+    fn resolves_lines_around_a_line() {
+        for (query, max_code_lines, from, to, context) in [
+            ("line=10", 25, 7, 13, 3),
+            ("line=10&context=5", 25, 5, 15, 5),
+            ("line=L10&context=0", 25, 10, 10, 0),
+            // Clamped to the start of the file.
+            ("line=2&context=5", 25, 1, 7, 5),
+            ("line=1", 25, 1, 4, 3),
+            // And to the end of the line numbers.
+            ("line=4294967294", 25, 4294967291, 4294967295, 3),
+            // The context is cut down to fit the line and both sides into `max_code_lines`.
+            ("line=100&context=50", 25, 88, 112, 12),
+            ("line=100&context=50", 10, 96, 104, 4),
+            // `lines` is ignored.
+            ("line=10&lines=1-2", 25, 7, 13, 3),
+        ] {
+            let lines = resolve_lines(query, max_code_lines).unwrap();
+            assert_eq!(
+                lines
+                    .ranges
+                    .iter()
+                    .map(|range| (range.from, range.to))
+                    .collect::<Vec<_>>(),
+                [(from, to)],
+                "{:?}",
+                query
+            );
+            assert_eq!(
+                lines.around.map(|(_, context)| context),
+                Some(context),
+                "{:?}",
+                query
+            );
+        }
+        assert!(resolve_lines("lines=1-2", 25).unwrap().around.is_none());
+        assert!(resolve_lines("context=5", 25).is_err());
+        assert!(resolve_lines("lines=1-2&context=5", 25).is_err());
+    }
+
+    #[actix_web::test]
+    async fn reads_lines_up_to_the_end_of_the_file() {
+        let code = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let read = |query: &'static str| async move {
+            let lines = resolve_lines(query, 25).unwrap();
+            let stream = futures_util::stream::iter([Ok::<_, ()>(code)]);
+            String::from_utf8(read_lines(stream, &lines, u32::MAX).await).unwrap()
+        };
+        assert_eq!(read("line=9").await, "6\n7\n8\n9\n10\n");
+        assert_eq!(read("line=2").await, "1\n2\n3\n4\n5");
+        assert_eq!(read("line=20").await, "");
+    }
+
+    #[test]
+    fn resolves_svg_resource_urls() {
+        let path = SrcPath {
+            author: "octocat".to_owned(),
+            repository: "hello".to_owned(),
+            branch: "main".to_owned(),
+            path: "docs/images/logo.svg".to_owned(),
+        };
+        let hosts = ["avatars.githubusercontent.com".to_owned()];
+        let resolve =
+            |href| resolve_svg_resource_url(&path, href, &hosts).map(|url| url.to_string());
+
+        for (href, url) in [
+            ("icon.png", "octocat/hello/main/docs/images/icon.png"),
+            ("../icon.png", "octocat/hello/main/docs/icon.png"),
+            ("./a/../icon.png", "octocat/hello/main/docs/images/icon.png"),
+            ("/assets/icon.png", "octocat/hello/main/assets/icon.png"),
+        ] {
+            assert_eq!(
+                resolve(href),
+                Some(format!("https://raw.githubusercontent.com/{}", url)),
+                "{:?}",
+                href
+            );
+        }
+        assert_eq!(
+            resolve("https://AVATARS.githubusercontent.com/u/1?v=4").as_deref(),
+            Some("https://avatars.githubusercontent.com/u/1?v=4")
+        );
+
+        for href in [
+            "../../../../other/repo/main/icon.png",
+            "%2e%2e/%2e%2e/%2e%2e/%2e%2e/other/icon.png",
+            "/../icon.png",
+            "//example.com/icon.png",
+            "https://example.com/icon.png",
+            "https://raw.githubusercontent.com/other/repo/main/icon.png",
+            "http://avatars.githubusercontent.com.example.com/icon.png",
+            "data:image/png;base64,AAAA",
+            "file:///etc/passwd",
+            "ftp://avatars.githubusercontent.com/icon.png",
+        ] {
+            assert_eq!(resolve(href), None, "{:?}", href);
+        }
+    }
+
+    #[test]
+    fn finds_font_files_by_family() {
+        let files = [
+            "FiraCode-Bold.ttf",
+            "fira_code.OTF",
+            "FiraCode.txt",
+            "Inter.otf",
+            "a.svg",
+        ]
+        .map(str::to_owned);
+        let families = ["Fira Code".to_owned(), " ".to_owned()];
+        assert_eq!(
+            font_files(&files, &families),
+            ["FiraCode-Bold.ttf", "fira_code.OTF"]
+        );
+        assert!(font_files(&files, &[]).is_empty());
     }
 }
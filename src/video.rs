@@ -0,0 +1,529 @@
+use std::time::Duration;
+
+use reqwest::{Client, StatusCode};
+
+use crate::utils::read_body;
+
+/// How much of a video is fetched at a time while looking for its header.
+const WINDOW_BYTES: u32 = 64 * 1024;
+/// The most range requests that are made for one video.
+const MAX_REQUESTS: usize = 8;
+/// The most top-level MP4 boxes or WebM elements that are looked through.
+const MAX_ELEMENTS: usize = 64;
+
+const EBML_SEGMENT: u32 = 0x1853_8067;
+const EBML_INFO: u32 = 0x1549_A966;
+const EBML_TRACKS: u32 = 0x1654_AE6B;
+const EBML_CLUSTER: u32 = 0x1F43_B675;
+const EBML_TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const EBML_DURATION: u32 = 0x4489;
+const EBML_TRACK_ENTRY: u32 = 0xAE;
+const EBML_TRACK_TYPE: u32 = 0x83;
+const EBML_VIDEO: u32 = 0xE0;
+const EBML_PIXEL_WIDTH: u32 = 0xB0;
+const EBML_PIXEL_HEIGHT: u32 = 0xBA;
+
+/// What the header of a video says about it.
+#[derive(Debug, Default)]
+pub(crate) struct VideoMetadata {
+    /// The width and height that the video is shown at.
+    pub(crate) size: Option<(u32, u32)>,
+    pub(crate) duration: Option<Duration>,
+}
+
+/// Reads the width, height and duration of the MP4, QuickTime or WebM video at `url` from its
+/// header, with range requests. Anything that can't be read is left out.
+pub(crate) async fn fetch_metadata(url: &str, mime: &str, max_bytes: u32) -> VideoMetadata {
+    let mut file = RangeReader::new(url, max_bytes);
+    let metadata = match mime {
+        "video/mp4" | "video/quicktime" | "video/x-m4v" => read_mp4(&mut file).await,
+        "video/webm" | "video/x-matroska" => read_ebml(&mut file).await,
+        _ => None,
+    };
+    metadata.unwrap_or_default()
+}
+
+/// Reads parts of a remote file, keeping the last range that was fetched.
+struct RangeReader<'a> {
+    client: Client,
+    url: &'a str,
+    max_bytes: u32,
+    requests: usize,
+    start: u64,
+    window: Vec<u8>,
+}
+
+impl<'a> RangeReader<'a> {
+    fn new(url: &'a str, max_bytes: u32) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            max_bytes,
+            requests: 0,
+            start: 0,
+            window: Vec::new(),
+        }
+    }
+
+    /// Up to `length` bytes from `offset`, which are fewer only at the end of the file.
+    async fn read(&mut self, offset: u64, length: u32) -> Option<&[u8]> {
+        let end = offset.checked_add(length as u64)?;
+        let covered = offset >= self.start && end <= self.start + self.window.len() as u64;
+        if !covered {
+            if self.requests >= MAX_REQUESTS || length > self.max_bytes {
+                return None;
+            }
+            self.requests += 1;
+            let length = length.max(WINDOW_BYTES).min(self.max_bytes);
+            let last = offset.checked_add((length as u64).checked_sub(1)?)?;
+            let response = self
+                .client
+                .get(self.url)
+                .header("Range", format!("bytes={}-{}", offset, last))
+                .send()
+                .await
+                .ok()?;
+            // A server that ignores the range sends the file from the start.
+            if !response.status().is_success()
+                || (response.status() != StatusCode::PARTIAL_CONTENT && offset > 0)
+            {
+                return None;
+            }
+            self.start = offset;
+            self.window = read_body(response.bytes_stream(), length).await;
+        }
+        let from = (offset - self.start) as usize;
+        let to = (end - self.start).min(self.window.len() as u64) as usize;
+        self.window.get(from..to)
+    }
+}
+
+/// Finds the `moov` box of an MP4 or QuickTime file, which may come after the media data.
+async fn read_mp4(file: &mut RangeReader<'_>) -> Option<VideoMetadata> {
+    let mut offset = 0;
+    for _ in 0..MAX_ELEMENTS {
+        let header = file.read(offset, 16).await?;
+        let (kind, header_length, size) = box_header(header)?;
+        if kind == b"moov" {
+            let size = u32::try_from(size?).ok()?;
+            let moov = file.read(offset, size).await?;
+            return Some(parse_moov(moov.get(header_length..)?));
+        }
+        // A box without a size runs to the end of the file.
+        offset = offset.checked_add(size?)?;
+    }
+    None
+}
+
+/// The type, header length and total size of the box at the start of `data`.
+fn box_header(data: &[u8]) -> Option<(&[u8], usize, Option<u64>)> {
+    let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+    let kind = data.get(4..8)?;
+    let (header_length, size) = match size {
+        0 => (8, None),
+        1 => (
+            16,
+            Some(u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)),
+        ),
+        size => (8, Some(size as u64)),
+    };
+    // A box can't be smaller than its own header.
+    size.is_none_or(|size| size >= header_length as u64)
+        .then_some((kind, header_length, size))
+}
+
+/// The boxes in `data`, as their type and contents.
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let (kind, header_length, size) = box_header(data)?;
+        let size = match size {
+            Some(size) => usize::try_from(size).ok()?,
+            None => data.len(),
+        };
+        let contents = data.get(header_length..size)?;
+        data = &data[size..];
+        Some((kind, contents))
+    })
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    boxes(data)
+        .find(|(found, _)| *found == kind)
+        .map(|(_, contents)| contents)
+}
+
+fn parse_moov(moov: &[u8]) -> VideoMetadata {
+    let mut metadata = VideoMetadata::default();
+    for (kind, contents) in boxes(moov) {
+        match kind {
+            b"mvhd" => metadata.duration = mvhd_duration(contents),
+            b"trak" if metadata.size.is_none() => metadata.size = video_track_size(contents),
+            _ => {}
+        }
+    }
+    metadata
+}
+
+fn mvhd_duration(mvhd: &[u8]) -> Option<Duration> {
+    let u32_at = |offset: usize| {
+        Some(u32::from_be_bytes(
+            mvhd.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let (timescale, duration) = match mvhd.first()? {
+        0 => (
+            u32_at(12)?,
+            u32_at(16).filter(|&duration| duration != u32::MAX)? as u64,
+        ),
+        1 => (
+            u32_at(20)?,
+            u64::from_be_bytes(mvhd.get(24..32)?.try_into().ok()?),
+        ),
+        _ => return None,
+    };
+    (timescale > 0 && duration != u64::MAX)
+        .then(|| Duration::from_secs_f64(duration as f64 / timescale as f64))
+}
+
+/// The size of a video track, turned by a quarter if its matrix rotates it, or `None` for other
+/// tracks.
+fn video_track_size(trak: &[u8]) -> Option<(u32, u32)> {
+    let handler = find_box(trak, b"mdia")
+        .and_then(|mdia| find_box(mdia, b"hdlr"))
+        .and_then(|hdlr| hdlr.get(8..12));
+    if handler != Some(b"vide") {
+        return None;
+    }
+
+    let tkhd = find_box(trak, b"tkhd")?;
+    let matrix = match tkhd.first()? {
+        0 => 40,
+        1 => 52,
+        _ => return None,
+    };
+    let u32_at = |offset: usize| {
+        Some(u32::from_be_bytes(
+            tkhd.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    // The dimensions are 16.16 fixed-point numbers after the matrix.
+    let width = u32_at(matrix + 36)? >> 16;
+    let height = u32_at(matrix + 40)? >> 16;
+    let rotated = u32_at(matrix)? == 0 && u32_at(matrix + 4)? != 0;
+    match (width, height) {
+        (0, _) | (_, 0) => None,
+        _ if rotated => Some((height, width)),
+        size => Some(size),
+    }
+}
+
+/// Finds the `Info` and `Tracks` elements of a WebM or Matroska file, which come before the first
+/// cluster of media data.
+async fn read_ebml(file: &mut RangeReader<'_>) -> Option<VideoMetadata> {
+    // The EBML header.
+    let header = file.read(0, 16).await?;
+    let (_, header_length, size) = ebml_header(header)?;
+    let mut offset = (header_length as u64).checked_add(size?)?;
+
+    let header = file.read(offset, 16).await?;
+    let (id, header_length, _) = ebml_header(header)?;
+    if id != EBML_SEGMENT {
+        return None;
+    }
+    offset = offset.checked_add(header_length as u64)?;
+
+    let mut metadata = VideoMetadata::default();
+    let mut timestamp_scale = 1_000_000;
+    let mut duration = None;
+    for _ in 0..MAX_ELEMENTS {
+        let Some(header) = file.read(offset, 16).await else {
+            break;
+        };
+        let Some((id, header_length, Some(size))) = ebml_header(header) else {
+            break;
+        };
+        let Some(length) = (header_length as u64).checked_add(size) else {
+            break;
+        };
+        match id {
+            EBML_INFO | EBML_TRACKS => {
+                let Ok(length) = u32::try_from(length) else {
+                    break;
+                };
+                let Some(element) = file.read(offset, length).await else {
+                    break;
+                };
+                let Some(contents) = element.get(header_length..) else {
+                    break;
+                };
+                if id == EBML_INFO {
+                    for (id, contents) in ebml_elements(contents) {
+                        match id {
+                            EBML_TIMESTAMP_SCALE => {
+                                timestamp_scale = ebml_uint(contents).unwrap_or(timestamp_scale)
+                            }
+                            EBML_DURATION => duration = ebml_float(contents),
+                            _ => {}
+                        }
+                    }
+                } else {
+                    metadata.size = ebml_elements(contents)
+                        .filter(|(id, _)| *id == EBML_TRACK_ENTRY)
+                        .find_map(|(_, entry)| ebml_video_size(entry));
+                }
+            }
+            EBML_CLUSTER => break,
+            _ => {}
+        }
+        let Some(next) = offset.checked_add(length) else {
+            break;
+        };
+        offset = next;
+    }
+
+    metadata.duration = duration
+        .map(|duration| duration * timestamp_scale as f64 / 1e9)
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64);
+    Some(metadata)
+}
+
+/// The ID, header length and size of the element at the start of `data`, or no size when it's
+/// unknown.
+fn ebml_header(data: &[u8]) -> Option<(u32, usize, Option<u64>)> {
+    let (id, id_length) = ebml_vint(data)?;
+    let id = u32::try_from(id | 1 << (7 * id_length)).ok()?;
+    let (size, size_length) = ebml_vint(data.get(id_length..)?)?;
+    let unknown = size == (1 << (7 * size_length)) - 1;
+    Some((id, id_length + size_length, (!unknown).then_some(size)))
+}
+
+/// A variable-length integer without its length marker, and its length in bytes.
+fn ebml_vint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 {
+        return None;
+    }
+    let value = data
+        .get(1..length)?
+        .iter()
+        .fold((first as u64) & (0xFF >> length), |value, &byte| {
+            value << 8 | byte as u64
+        });
+    Some((value, length))
+}
+
+/// The elements in `data`, as their ID and contents.
+fn ebml_elements(mut data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, header_length, size) = ebml_header(data)?;
+        let end = header_length.checked_add(usize::try_from(size?).ok()?)?;
+        let contents = data.get(header_length..end)?;
+        data = &data[end..];
+        Some((id, contents))
+    })
+}
+
+fn ebml_uint(data: &[u8]) -> Option<u64> {
+    (data.len() <= 8).then(|| data.iter().fold(0, |value, &byte| value << 8 | byte as u64))
+}
+
+fn ebml_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// The pixel size of a track entry, or `None` if it isn't a video track.
+fn ebml_video_size(entry: &[u8]) -> Option<(u32, u32)> {
+    let mut is_video = false;
+    let mut size = None;
+    for (id, contents) in ebml_elements(entry) {
+        match id {
+            EBML_TRACK_TYPE => is_video = ebml_uint(contents) == Some(1),
+            EBML_VIDEO => {
+                let mut width = None;
+                let mut height = None;
+                for (id, contents) in ebml_elements(contents) {
+                    match id {
+                        EBML_PIXEL_WIDTH => width = ebml_uint(contents),
+                        EBML_PIXEL_HEIGHT => height = ebml_uint(contents),
+                        _ => {}
+                    }
+                }
+                size = width.zip(height).and_then(|(width, height)| {
+                    Some((u32::try_from(width).ok()?, u32::try_from(height).ok()?))
+                });
+            }
+            _ => {}
+        }
+    }
+    size.filter(|_| is_video)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader that has all of `data` already and never makes a request.
+    fn reader(data: Vec<u8>) -> RangeReader<'static> {
+        RangeReader {
+            requests: MAX_REQUESTS,
+            window: data,
+            ..RangeReader::new("", 1024 * 1024)
+        }
+    }
+
+    fn mp4_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut data = (8 + contents.len() as u32).to_be_bytes().to_vec();
+        data.extend(kind);
+        data.extend(contents);
+        data
+    }
+
+    /// A `moov` box with a 90 second movie and a 1920×1080 video track that may be rotated.
+    fn moov(rotated: bool) -> Vec<u8> {
+        let mut mvhd = vec![0; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&90_000u32.to_be_bytes());
+
+        let mut tkhd = vec![0; 84];
+        let (a, b): (u32, u32) = if rotated {
+            (0, 0x1_0000)
+        } else {
+            (0x1_0000, 0)
+        };
+        tkhd[40..44].copy_from_slice(&a.to_be_bytes());
+        tkhd[44..48].copy_from_slice(&b.to_be_bytes());
+        tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+        let mut hdlr = vec![0; 24];
+        hdlr[8..12].copy_from_slice(b"vide");
+        let mdia = mp4_box(b"mdia", &mp4_box(b"hdlr", &hdlr));
+        let trak = mp4_box(b"trak", &[mp4_box(b"tkhd", &tkhd), mdia].concat());
+
+        mp4_box(b"moov", &[mp4_box(b"mvhd", &mvhd), trak].concat())
+    }
+
+    #[actix_web::test]
+    async fn reads_mp4_headers_after_the_media_data() {
+        let ftyp = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        let mdat = mp4_box(b"mdat", &[0; 100]);
+
+        let file = [ftyp.clone(), mdat.clone(), moov(false)].concat();
+        let metadata = read_mp4(&mut reader(file)).await.unwrap();
+        assert_eq!(metadata.size, Some((1920, 1080)));
+        assert_eq!(metadata.duration, Some(Duration::from_secs(90)));
+
+        let file = [ftyp, mdat, moov(true)].concat();
+        let metadata = read_mp4(&mut reader(file)).await.unwrap();
+        assert_eq!(metadata.size, Some((1080, 1920)));
+    }
+
+    #[actix_web::test]
+    async fn rejects_malformed_mp4_boxes() {
+        // A box whose 64-bit size would run past the end of any offset.
+        let mut huge = 1u32.to_be_bytes().to_vec();
+        huge.extend(b"free");
+        huge.extend((u64::MAX - 4).to_be_bytes());
+        let file = [mp4_box(b"ftyp", b"isom"), huge, moov(false)].concat();
+        assert!(read_mp4(&mut reader(file)).await.is_none());
+
+        // Boxes smaller than their header, and one that runs to the end of the file.
+        let mut small = 1u32.to_be_bytes().to_vec();
+        small.extend(b"free");
+        small.extend(8u64.to_be_bytes());
+        assert!(box_header(&small).is_none());
+        assert!(box_header(b"\0\0\0\x07free").is_none());
+        let file = [0u32.to_be_bytes().as_slice(), b"mdat", &moov(false)].concat();
+        assert!(read_mp4(&mut reader(file)).await.is_none());
+
+        // A truncated `moov` box.
+        let moov = moov(false);
+        assert!(read_mp4(&mut reader(moov[..moov.len() - 1].to_vec()))
+            .await
+            .is_none());
+        assert!(boxes(b"\0\0\0\x10moov\0\0").next().is_none());
+    }
+
+    fn element(id: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.push(0x01);
+        data.extend(&(contents.len() as u64).to_be_bytes()[1..]);
+        data.extend(contents);
+        data
+    }
+
+    #[actix_web::test]
+    async fn reads_webm_headers() {
+        let info = element(
+            &[0x15, 0x49, 0xA9, 0x66],
+            &[
+                element(&[0x2A, 0xD7, 0xB1], &[0x0F, 0x42, 0x40]),
+                element(&[0x44, 0x89], &2500f32.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let audio = element(&[0xAE], &element(&[0x83], &[2]));
+        let video = element(
+            &[0xAE],
+            &[
+                element(&[0x83], &[1]),
+                element(
+                    &[0xE0],
+                    &[
+                        element(&[0xB0], &[0x02, 0x80]),
+                        element(&[0xBA], &[0x01, 0xE0]),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        let tracks = element(&[0x16, 0x54, 0xAE, 0x6B], &[audio, video].concat());
+        let cluster = element(&[0x1F, 0x43, 0xB6, 0x75], &[0; 16]);
+        // A segment of unknown size.
+        let segment = [
+            &[0x18, 0x53, 0x80, 0x67, 0xFF][..],
+            &info,
+            &tracks,
+            &cluster,
+        ]
+        .concat();
+        let file = [element(&[0x1A, 0x45, 0xDF, 0xA3], &[]), segment].concat();
+
+        let metadata = read_ebml(&mut reader(file)).await.unwrap();
+        assert_eq!(metadata.size, Some((640, 480)));
+        assert_eq!(metadata.duration, Some(Duration::from_millis(2500)));
+    }
+
+    #[actix_web::test]
+    async fn rejects_malformed_webm_elements() {
+        assert!(ebml_vint(&[0]).is_none());
+        assert!(ebml_vint(&[0x40]).is_none());
+        assert_eq!(ebml_vint(&[0x81]), Some((1, 1)));
+        assert_eq!(ebml_header(&[0xEC, 0xFF]), Some((0xEC, 2, None)));
+        assert!(ebml_elements(&element(&[0xEC], &[0; 4])[..8])
+            .next()
+            .is_none());
+
+        // An EBML header of unknown size, and one whose size runs past the file.
+        let file = vec![0x1A, 0x45, 0xDF, 0xA3, 0xFF];
+        assert!(read_ebml(&mut reader(file)).await.is_none());
+        let mut file = vec![0x1A, 0x45, 0xDF, 0xA3, 0x01];
+        file.extend([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+        assert!(read_ebml(&mut reader(file)).await.is_none());
+
+        // An element in the segment that's bigger than the file ends the search.
+        let mut segment = vec![0x18, 0x53, 0x80, 0x67, 0xFF, 0xEC, 0x01];
+        segment.extend([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+        // Each header is read as 16 bytes.
+        segment.extend([0; 16]);
+        let file = [element(&[0x1A, 0x45, 0xDF, 0xA3], &[]), segment].concat();
+        let metadata = read_ebml(&mut reader(file)).await.unwrap();
+        assert!(metadata.size.is_none() && metadata.duration.is_none());
+    }
+}